@@ -0,0 +1,111 @@
+// Copyright (c) 2017 repomons developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `repomons` client authentication.
+//!
+//! When tokens are configured, a client must send an `Authenticate` request
+//! with one of them before it is sent anything else.  The token decides which
+//! repositories the client may see.  Clients that have not authenticated
+//! before the timeout are disconnected.
+use config::TokenConfig;
+use error::{Error, Result};
+use filter::Filter;
+use futures::future::{self, Either};
+use futures::Future;
+use protocol::Subscription;
+use server::{Peer, Server};
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::rc::Rc;
+use tokio_core::reactor::{Handle, Timeout};
+
+/// A shared client token.
+#[derive(Clone, Debug)]
+pub struct Token {
+    /// The token value.
+    token: String,
+    /// The repositories the token grants access to.
+    access: Filter,
+}
+
+impl TryFrom<&TokenConfig> for Token {
+    type Error = Error;
+
+    fn try_from(config: &TokenConfig) -> Result<Self> {
+        let mut subscription: Subscription = Default::default();
+        subscription.set_repos(config.repos().clone());
+
+        Ok(Self {
+            token: config.token().clone(),
+            access: Filter::try_from(&subscription)?,
+        })
+    }
+}
+
+impl Token {
+    /// The repositories the token grants access to, if it matches the given token.
+    pub fn verify(&self, token: &str) -> Option<&Filter> {
+        if constant_time_eq(self.token.as_bytes(), token.as_bytes()) {
+            Some(&self.access)
+        } else {
+            None
+        }
+    }
+}
+
+/// Compare two byte strings in time independent of where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// A future that resolves if the client at the given address has not
+/// authenticated by the configured timeout, and never otherwise.
+pub fn deadline(
+    handle: &Handle,
+    server: &Rc<RefCell<Server>>,
+    addr: Peer,
+) -> Box<dyn Future<Item = (), Error = ()>> {
+    if server.borrow().is_authenticated(&addr) {
+        return Box::new(future::empty());
+    }
+
+    let timeout = match Timeout::new(*server.borrow().auth_timeout(), handle) {
+        Ok(timeout) => timeout,
+        Err(_) => return Box::new(future::ok(())),
+    };
+    let server = Rc::clone(server);
+    Box::new(timeout.map_err(|_| ()).and_then(move |_| {
+        if server.borrow().is_authenticated(&addr) {
+            Either::A(future::empty())
+        } else {
+            Either::B(future::ok(()))
+        }
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::Token;
+    use config::TokenConfig;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn verify() {
+        let mut config: TokenConfig = Default::default();
+        config.set_token("s3cret".to_string());
+        config.set_repos(vec!["repomon*".to_string()]);
+        let token = Token::try_from(&config).expect("invalid token");
+
+        let access = token.verify("s3cret").expect("token not verified");
+        assert!(access.matches_repo("repomons"));
+        assert!(!access.matches_repo("ar2"));
+        assert!(token.verify("s3cre").is_none());
+        assert!(token.verify("s3cres").is_none());
+        assert!(token.verify("").is_none());
+    }
+}
@@ -7,35 +7,41 @@
 // modified, or distributed except according to those terms.
 
 //! branch related operations
-use bincode::{serialize, Infinite};
 use callbacks::{self, CallbackOutput};
 use colored::*;
+use config::EventConfig;
 use error::Result;
+use event::{
+    BranchStatus, CommitSummary, DiffStat, Event, MirrorStatus, PathStat, RemoteStatus, Update,
+};
 use futures::future::result;
 use futures::sync::mpsc;
 use futures::{Future, Sink};
 use git2::{
-    self, AutotagOption, Direction, FetchOptions, FetchPrune, Oid, ProxyOptions, Repository, Status,
+    self, AutotagOption, Direction, FetchOptions, FetchPrune, Oid, Patch, ProxyOptions, Repository,
+    Sort, Status,
 };
+use glob::Pattern;
 use log::Logs;
-use rand::{self, Rng};
+use monitors;
 use repo::{self, Config};
-use repomon::{Branch, Category, Message, Remote};
-use std::collections::{BTreeMap, HashMap};
+use repomon::{Branch, Message, Remote};
+use shutdown::Shutdown;
+use std::collections::{BTreeMap, HashSet};
 use std::path::PathBuf;
-use std::thread;
-use std::time::{Duration, Instant};
+use std::time::Instant;
 use uuid::Uuid;
 
 /// Sender type for monitor.
-type SenderType = mpsc::UnboundedSender<::std::result::Result<Vec<u8>, ()>>;
+type SenderType = mpsc::UnboundedSender<::std::result::Result<Update, ()>>;
 
 /// Repository monitor configuration.
 #[derive(Clone, Getters, Setters)]
 pub struct MonitorConfig {
-    /// The base directory to start repository discovery.
+    /// The absolute path of the repository.
     #[get]
-    basedir: String,
+    #[set = "pub"]
+    path: PathBuf,
     /// The mpsc sender type.
     #[get]
     tx: SenderType,
@@ -45,11 +51,18 @@ pub struct MonitorConfig {
     /// The remote handle to the event loop.
     #[get]
     remote_handle: ::tokio_core::reactor::Remote,
+    /// The server shutdown state.
+    #[get]
+    shutdown: Shutdown,
+    /// The event detail settings.
+    #[get]
+    #[set = "pub"]
+    events: EventConfig,
     #[get]
     #[set = "pub"]
     /// The repository name.
     repo_name: String,
-    #[get]
+    #[get = "pub"]
     #[set = "pub"]
     /// The branch we are monitoring.
     branch: Branch,
@@ -57,253 +70,538 @@ pub struct MonitorConfig {
     #[set = "pub"]
     /// The remotes we are comparing this branch against.
     remotes: Vec<Remote>,
+    #[get]
+    #[set = "pub"]
+    /// The remote the other remotes mirror, if any.
+    mirror_source: Option<String>,
+    #[get]
+    #[set]
+    /// The branch pattern this branch was matched by, if any.
+    pattern: Option<String>,
+    #[get]
+    #[set = "pub"]
+    /// The branches of the repository monitored by name, which this branch
+    /// pattern leaves out, whether or not they are checked with it.
+    named: Vec<String>,
 }
 
 impl MonitorConfig {
     /// Create a new configuration for this monitor.
     pub fn new(
-        basedir: &str,
         tx: SenderType,
         logs: Logs,
         remote_handle: ::tokio_core::reactor::Remote,
+        shutdown: Shutdown,
     ) -> Self {
         Self {
-            basedir: basedir.to_string(),
+            path: Default::default(),
             tx,
             logs,
             remote_handle,
+            shutdown,
+            events: Default::default(),
             repo_name: Default::default(),
             branch: Default::default(),
             remotes: Default::default(),
+            mirror_source: Default::default(),
+            pattern: Default::default(),
+            named: Default::default(),
         }
     }
 }
 
-/// Monitor
-pub fn monitor(config: &MonitorConfig) -> Result<()> {
-    try_trace!(
-        config.logs().stdout(),
-        "Starting monitor thread";
-        "repository" => config.repo_name(),
-        "branch" => format!("{}", config.branch())
-    );
-
-    // Grab so info out of the branch config
-    let interval = config.branch().interval_to_ms()?;
-    let branch_name = config.branch().name();
+/// Check every given branch of a repository against its remotes once,
+/// sending the results to the clients.  Each remote is fetched once, for
+/// every branch monitored on it.  Branch patterns are expanded against the
+/// branches found on their remotes.  Scheduling the checks is left to the
+/// `scheduler`.
+pub fn check(configs: &[MonitorConfig]) -> Result<()> {
+    let config = match configs.first() {
+        Some(config) => config,
+        None => return Ok(()),
+    };
     let repo_name = config.repo_name();
-
-    // Setup the base message.
-    let mut message: Message = Default::default();
-    message.set_repo(repo_name.clone());
-
-    // Delay start up to 80% to avoid running all the same intervals
-    // at the same time.
-    let mut rng = rand::thread_rng();
-    let rand_delay = rng.gen_range(0..(interval * 4) / 5) as u64;
     try_trace!(
         config.logs().stdout(),
-        "Delaying monitor start";
-        "ms" => rand_delay,
+        "Checking repository";
         "repository" => repo_name,
-        "branch" => branch_name
+        "branches" => configs.len()
     );
-    thread::sleep(Duration::from_millis(rand_delay));
+
+    // Metrics
+    let now = Instant::now();
 
     // Setup some config, used to discover/clone the repository
     let mut repo_config: Config = Default::default();
-    repo_config.set_basedir(PathBuf::from(config.basedir()));
-    repo_config.set_repo(PathBuf::from(repo_name));
+    repo_config.set_path(config.path().clone());
     repo_config.set_remotes(config.remotes());
+    repo_config.set_cancelled(config.shutdown().cancelled());
 
     let repo = repo::discover_or_clone(&repo_config)?;
     repo::check_remotes(&repo, &repo_config)?;
 
-    loop {
-        // Add some loop specific information to the message.
-        let mut msg_clone = message.clone();
-        msg_clone.set_uuid(Uuid::new_v4());
-
-        // Metrics
-        let now = Instant::now();
-
-        // Run a fetch on the remotes we are monitoring.
+    // Group the monitored branches by remote.
+    let mut remote_branches: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for config in configs {
         for remote in config.branch().remotes() {
-            let mut git_remote = repo.find_remote(remote)?;
-
-            let mut proxy_opts = ProxyOptions::new();
-            proxy_opts.auto();
-
-            let connect_output: CallbackOutput = Default::default();
-            let connect_callbacks = callbacks::get_default(connect_output)?;
-            git_remote.connect_auth(Direction::Fetch, Some(connect_callbacks), Some(proxy_opts))?;
-
-            let mut proxy_opts = ProxyOptions::new();
-            proxy_opts.auto();
-
-            let download_output: CallbackOutput = Default::default();
-            let download_callbacks = callbacks::get_default(download_output)?;
-
-            let mut fetch_opts = FetchOptions::new();
-            fetch_opts.remote_callbacks(download_callbacks);
-            fetch_opts.proxy_options(proxy_opts);
-            fetch_opts.prune(FetchPrune::On);
-
-            let mut valid_branchname = false;
-            for remote_head in git_remote.list()? {
-                let rh_name = remote_head.name();
-                let mut remote_branch_name = String::from("refs/heads/");
-                remote_branch_name.push_str(branch_name);
-
-                if rh_name == remote_branch_name {
-                    try_trace!(
-                        config.logs.stdout(),
-                        "Found matching remote branch";
-                        "remote_ref" => rh_name,
-                        "branch" => branch_name,
-                        "repo" => repo_name
-                    );
-                    valid_branchname = true;
-                    break;
-                }
-            }
+            remote_branches
+                .entry(remote)
+                .or_default()
+                .push(config.branch().name());
+        }
+    }
 
-            if valid_branchname {
-                git_remote.download(&[branch_name], Some(&mut fetch_opts))?;
-
-                let update_output: CallbackOutput = Default::default();
-                let mut update_callbacks = callbacks::get_default(update_output)?;
-                git_remote.update_tips(
-                    Some(&mut update_callbacks),
-                    true,
-                    AutotagOption::Auto,
-                    None,
-                )?;
-            } else {
+    // Run a single fetch on each of the remotes we are monitoring.
+    let mut found = HashSet::new();
+    for (remote, branch_names) in &remote_branches {
+        // Don't start new fetches once the server is shutting down.
+        if config.shutdown().is_stopping() {
+            return Ok(());
+        }
+        for branch_name in fetch(config, &repo, remote, branch_names)? {
+            let _ = found.insert((remote.to_string(), branch_name));
+        }
+    }
+
+    // Branches monitored by name are not also checked for a pattern.
+    let named: HashSet<&str> = configs
+        .iter()
+        .flat_map(|config| config.named())
+        .map(String::as_str)
+        .collect();
+
+    for config in configs {
+        let branch_name = config.branch().name();
+        if monitors::is_pattern(branch_name) {
+            if let Err(e) = compare_matched(config, &repo, &found, &named) {
                 try_error!(
                     config.logs().stderr(),
-                    "Invalid branch";
+                    "Error checking branch pattern: {}", e;
                     "repository" => repo_name,
-                    "branch" => branch_name
+                    "pattern" => branch_name
                 );
-                return Err(format!("invalid branch: {}", branch_name).into());
             }
+            continue;
         }
 
-        let local_branch_oid = vec![get_oid_by_spec(&repo, branch_name)?];
-        let remote_oids = config
+        let missing = config
             .branch()
             .remotes()
             .iter()
-            .map(|x| {
-                let mut remote_name = x.clone();
-                remote_name.push('/');
-                remote_name.push_str(branch_name);
-                remote_name
-            })
-            .map(|remote_name| {
-                (
-                    remote_name.clone(),
-                    get_oid_by_spec(&repo, &remote_name).expect(""),
-                )
-            })
-            .collect::<HashMap<String, Oid>>();
+            .find(|remote| !found.contains(&(remote.to_string(), branch_name.clone())));
+
+        if let Some(remote) = missing {
+            try_error!(
+                config.logs().stderr(),
+                "Invalid branch";
+                "repository" => repo_name,
+                "branch" => branch_name,
+                "remote" => remote
+            );
+        } else if let Err(e) = compare(config, &repo) {
+            try_error!(
+                config.logs().stderr(),
+                "Error checking branch: {}", e;
+                "repository" => repo_name,
+                "branch" => branch_name
+            );
+        }
+    }
 
-        let mut messages = BTreeMap::new();
-        let mut branch: Branch = Default::default();
+    try_trace!(
+        config.logs().stdout(),
+        "Duration: {}.{}",
+        now.elapsed().as_secs(),
+        now.elapsed().subsec_millis();
+        "repository" => repo_name
+    );
+
+    Ok(())
+}
+
+/// Compare every branch matching a pattern against the remotes it was found
+/// on, after telling the server which branches the pattern matched.
+fn compare_matched(
+    config: &MonitorConfig,
+    repo: &Repository,
+    found: &HashSet<(String, String)>,
+    named: &HashSet<&str>,
+) -> Result<()> {
+    let pattern_name = config.branch().name();
+    let pattern = Pattern::new(pattern_name)?;
+
+    let mut matched: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for remote in config.branch().remotes() {
+        for (found_remote, branch_name) in found {
+            if found_remote == remote
+                && pattern.matches(branch_name)
+                && !named.contains(branch_name.as_str())
+            {
+                matched.entry(branch_name).or_default().push(remote.clone());
+            }
+        }
+    }
+    send(
+        config,
+        Update::Matched(
+            config.repo_name().clone(),
+            pattern_name.clone(),
+            matched.keys().map(|name| name.to_string()).collect(),
+        ),
+    );
+
+    for (branch_name, remotes) in matched {
+        let mut branch = config.branch().clone();
         branch.set_name(branch_name.to_string());
+        branch.set_remotes(remotes);
+        let mut matched_config = config.clone();
+        matched_config.set_branch(branch);
+        matched_config.set_pattern(Some(pattern_name.clone()));
+
+        if let Err(e) = compare(&matched_config, repo) {
+            try_error!(
+                config.logs().stderr(),
+                "Error checking branch: {}", e;
+                "repository" => config.repo_name(),
+                "branch" => branch_name,
+                "pattern" => pattern_name
+            );
+        }
+    }
 
-        let mut remote_messages = BTreeMap::new();
+    Ok(())
+}
 
-        for (local_oid, (remote_name, remote_oid)) in
-            local_branch_oid.iter().cycle().zip(remote_oids.iter())
+/// Fetch the given branches, and the branches matching the given patterns,
+/// from a remote, returning the names of those found on the remote.  Every
+/// branch found on the remote is sent to the server, which reports the
+/// branches created, deleted and renamed since the last check.
+fn fetch(
+    config: &MonitorConfig,
+    repo: &Repository,
+    remote: &str,
+    branch_names: &[&str],
+) -> Result<Vec<String>> {
+    let repo_name = config.repo_name();
+    let mut git_remote = repo.find_remote(remote)?;
+
+    let mut proxy_opts = ProxyOptions::new();
+    proxy_opts.auto();
+
+    let mut connect_output: CallbackOutput = Default::default();
+    connect_output.set_cancelled(config.shutdown().cancelled());
+    let connect_callbacks = callbacks::get_default(connect_output)?;
+    git_remote.connect_auth(Direction::Fetch, Some(connect_callbacks), Some(proxy_opts))?;
+
+    let mut proxy_opts = ProxyOptions::new();
+    proxy_opts.auto();
+
+    let mut download_output: CallbackOutput = Default::default();
+    download_output.set_cancelled(config.shutdown().cancelled());
+    let download_callbacks = callbacks::get_default(download_output)?;
+
+    let mut fetch_opts = FetchOptions::new();
+    fetch_opts.remote_callbacks(download_callbacks);
+    fetch_opts.proxy_options(proxy_opts);
+    fetch_opts.prune(FetchPrune::On);
+
+    let patterns = branch_names
+        .iter()
+        .filter(|branch_name| monitors::is_pattern(branch_name))
+        .map(|branch_name| Pattern::new(branch_name))
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+
+    let mut valid_branchnames = Vec::new();
+    let mut refs = BTreeMap::new();
+    for remote_head in git_remote.list()? {
+        let rh_name = remote_head.name();
+        let branch_name = match rh_name.strip_prefix("refs/heads/") {
+            Some(branch_name) => branch_name,
+            None => continue,
+        };
+        let _ = refs.insert(branch_name.to_string(), remote_head.oid().to_string());
+        if branch_names.contains(&branch_name)
+            || patterns.iter().any(|pattern| pattern.matches(branch_name))
         {
-            let mut remote: Remote = Default::default();
-            remote.set_name(remote_name.to_string());
+            try_trace!(
+                config.logs.stdout(),
+                "Found matching remote branch";
+                "remote_ref" => rh_name,
+                "branch" => branch_name,
+                "repo" => repo_name
+            );
+            valid_branchnames.push(branch_name.to_string());
+        }
+    }
+    send(
+        config,
+        Update::Refs(repo_name.clone(), remote.to_string(), refs),
+    );
 
-            let (ahead, behind) = repo.graph_ahead_behind(*local_oid, *remote_oid)?;
-
-            if ahead > 0 || behind > 0 {
-                let mut message = if ahead > 0 {
-                    msg_clone.set_category(Category::Ahead);
-                    format!(
-                        "{}{}{}{}{}",
-                        "Your branch is ahead of '".green(),
-                        remote_name.green(),
-                        "' by ".green(),
-                        ahead.to_string().green(),
-                        " commit(s)".green()
-                    )
-                } else {
-                    String::new()
-                };
-
-                message = if behind > 0 {
-                    msg_clone.set_category(Category::Behind);
-                    format!(
-                        "{}{}{}{}{}",
-                        "Your branch is behind '".green(),
-                        remote_name.green(),
-                        "' by ".green(),
-                        behind.to_string().green(),
-                        " commit(s)".green()
-                    )
-                } else {
-                    message
-                };
-
-                try_info!(
-                    config.logs().stdout(),
-                    "{}",
-                    message;
-                    "repository" => repo_name,
-                    "branch" => branch_name
-                );
-                remote_messages.insert(remote, message);
+    if !valid_branchnames.is_empty() {
+        git_remote.download(&valid_branchnames, Some(&mut fetch_opts))?;
+
+        let mut update_output: CallbackOutput = Default::default();
+        update_output.set_cancelled(config.shutdown().cancelled());
+        let mut update_callbacks = callbacks::get_default(update_output)?;
+        git_remote.update_tips(Some(&mut update_callbacks), true, AutotagOption::Auto, None)?;
+    }
+
+    Ok(valid_branchnames)
+}
+
+/// Compare a fetched branch against its remotes, sending the result to the
+/// clients.
+fn compare(config: &MonitorConfig, repo: &Repository) -> Result<()> {
+    // Grab so info out of the branch config
+    let branch_name = config.branch().name();
+    let repo_name = config.repo_name();
+
+    // Setup the message.
+    let mut msg_clone: Message = Default::default();
+    msg_clone.set_repo(repo_name.clone());
+    msg_clone.set_uuid(Uuid::new_v4());
+
+    let local_branch_oid = [local_oid(config, repo)?];
+    let remote_oids = config
+        .branch()
+        .remotes()
+        .iter()
+        .map(|x| {
+            let mut remote_name = x.clone();
+            remote_name.push('/');
+            remote_name.push_str(branch_name);
+            (x, remote_name)
+        })
+        .map(|(upstream, remote_name)| {
+            let remote_oid = get_oid_by_spec(repo, &remote_name)?;
+            Ok((remote_name, (upstream, remote_oid)))
+        })
+        .collect::<Result<BTreeMap<String, (&String, Oid)>>>()?;
+
+    let mut messages = BTreeMap::new();
+    let mut branch: Branch = Default::default();
+    branch.set_name(branch_name.to_string());
+
+    let mut remote_messages = BTreeMap::new();
+    let mut remote_statuses = Vec::new();
+
+    for (local_oid, (remote_name, (upstream, remote_oid))) in
+        local_branch_oid.iter().cycle().zip(remote_oids.iter())
+    {
+        let mut remote: Remote = Default::default();
+        remote.set_name(remote_name.to_string());
+
+        let (ahead, behind) = repo.graph_ahead_behind(*local_oid, *remote_oid)?;
+        let merge_base = repo.merge_base(*local_oid, *remote_oid).ok();
+        let max_commits = *config.events().max_commits();
+        let mut remote_status = RemoteStatus::new(upstream, ahead, behind);
+        remote_status.set_merge_base(merge_base.map(|oid| oid.to_string()));
+        remote_status.set_incoming(commits(repo, *remote_oid, *local_oid, max_commits)?);
+        remote_status.set_outgoing(commits(repo, *local_oid, *remote_oid, max_commits)?);
+        // Only count the incoming changes, so the local commits of a branch
+        // that is ahead or diverged are not counted as deletions.
+        let base = merge_base.unwrap_or(*local_oid);
+        if base != *remote_oid {
+            let max_paths = *config.events().max_paths();
+            remote_status.set_diffstat(diffstat(repo, base, *remote_oid, max_paths)?);
+        }
+        remote_statuses.push(remote_status);
+
+        if ahead > 0 && behind > 0 {
+            let mut message = format!(
+                "{}{}{}{}{}{}{}",
+                "Your branch and '".green(),
+                remote_name.green(),
+                "' have diverged, and have ".green(),
+                ahead.to_string().green(),
+                " and ".green(),
+                behind.to_string().green(),
+                " different commit(s) each, respectively".green()
+            );
+            if let Some(oid) = merge_base {
+                message.push_str(&format!(" (merge base {:.7})", oid.to_string()));
+            }
+
+            try_info!(
+                config.logs().stdout(),
+                "{}",
+                message;
+                "repository" => repo_name,
+                "branch" => branch_name
+            );
+            remote_messages.insert(remote, message);
+        } else if ahead > 0 || behind > 0 {
+            let mut message = if ahead > 0 {
+                format!(
+                    "{}{}{}{}{}",
+                    "Your branch is ahead of '".green(),
+                    remote_name.green(),
+                    "' by ".green(),
+                    ahead.to_string().green(),
+                    " commit(s)".green()
+                )
             } else {
-                msg_clone.set_category(Category::UpToDate);
-                let message = format!("Your branch is up to date with '{}'", remote_name);
-                try_trace!(
-                    config.logs().stdout(),
-                    "{}",
-                    message;
-                    "repository" => repo_name,
-                    "branch" => branch_name
-                );
-                remote_messages.insert(remote, message);
+                String::new()
+            };
+
+            message = if behind > 0 {
+                format!(
+                    "{}{}{}{}{}",
+                    "Your branch is behind '".green(),
+                    remote_name.green(),
+                    "' by ".green(),
+                    behind.to_string().green(),
+                    " commit(s)".green()
+                )
+            } else {
+                message
+            };
+
+            try_info!(
+                config.logs().stdout(),
+                "{}",
+                message;
+                "repository" => repo_name,
+                "branch" => branch_name
+            );
+            remote_messages.insert(remote, message);
+        } else {
+            let message = format!("Your branch is up to date with '{}'", remote_name);
+            try_trace!(
+                config.logs().stdout(),
+                "{}",
+                message;
+                "repository" => repo_name,
+                "branch" => branch_name
+            );
+            remote_messages.insert(remote, message);
+        }
+    }
+
+    // Compare the mirrors against their source.
+    let mut mirror_statuses = Vec::new();
+    if let Some(source) = config.mirror_source() {
+        let source_oid = remote_oids
+            .values()
+            .find(|(upstream, _)| *upstream == source)
+            .map(|(_, oid)| *oid);
+        for (remote_name, (upstream, remote_oid)) in &remote_oids {
+            let source_oid = match source_oid {
+                Some(source_oid) if *upstream != source => source_oid,
+                _ => continue,
+            };
+
+            let (ahead, behind) = repo.graph_ahead_behind(*remote_oid, source_oid)?;
+            let mirror_status = MirrorStatus::new(upstream, source, ahead, behind);
+            let message = mirror_status.message(branch_name);
+            if behind > 0 {
+                try_info!(config.logs().stdout(), "{}", message; "repository" => repo_name, "branch" => branch_name);
+            } else {
+                try_trace!(config.logs().stdout(), "{}", message; "repository" => repo_name, "branch" => branch_name);
             }
+
+            let mut remote: Remote = Default::default();
+            remote.set_name(remote_name.to_string());
+            if let Some(text) = remote_messages.get_mut(&remote) {
+                text.push_str("; ");
+                text.push_str(&message);
+            }
+            mirror_statuses.push(mirror_status);
         }
+    }
+    mirror_statuses.sort_by(|a, b| a.mirror().cmp(b.mirror()));
 
-        messages.insert(branch, remote_messages);
-        msg_clone.set_messages(messages);
+    messages.insert(branch, remote_messages);
+    msg_clone.set_messages(messages);
 
-        let f = result::<(), ()>(Ok(()));
-        let tx = config.tx().clone();
+    let mut status = BranchStatus::new(repo_name, branch_name);
+    status.set_remotes(remote_statuses);
+    status.set_mirrors(mirror_statuses);
+    msg_clone.set_category(status.category());
+    send(config, Update::Event(Event::new(msg_clone, status)));
+
+    Ok(())
+}
 
-        config.remote_handle().spawn(|_| {
-            f.then(move |_res| {
-                let encoded = serialize(&msg_clone, Infinite).expect("");
-                tx.send(Ok(encoded)).then(|tx| match tx {
-                    Ok(_tx) => Ok(()),
-                    Err(_e) => Err(()),
-                })
+/// Send an update to the server, on the event loop.
+fn send(config: &MonitorConfig, update: Update) {
+    let f = result::<(), ()>(Ok(()));
+    let tx = config.tx().clone();
+
+    config.remote_handle().spawn(|_| {
+        f.then(move |_res| {
+            tx.send(Ok(update)).then(|tx| match tx {
+                Ok(_tx) => Ok(()),
+                Err(_e) => Err(()),
             })
-        });
-
-        try_trace!(
-            config.logs().stdout(),
-            "Duration: {}.{}",
-            now.elapsed().as_secs(),
-            now.elapsed().subsec_millis();
-            "repository" => repo_name,
-            "branch" => branch_name
-        );
-
-        // Sleep until the interval has passed.
-        let int: u64 = interval as u64;
-        try_trace!(config.logs().stdout(), "Sleeping"; "interval" => int, "repository" => repo_name, "branch" => branch_name);
-        thread::sleep(Duration::from_millis(int));
+        })
+    });
+}
+
+/// The OID of the local branch.  A branch matched by a pattern may not exist
+/// locally, and is then compared against the branch on its first remote.
+fn local_oid(config: &MonitorConfig, repo: &Repository) -> Result<Oid> {
+    let branch_name = config.branch().name();
+    match get_oid_by_spec(repo, branch_name) {
+        Ok(oid) => Ok(oid),
+        Err(e) => match (config.pattern(), config.branch().remotes().first()) {
+            (Some(_), Some(remote)) => {
+                get_oid_by_spec(repo, &format!("{}/{}", remote, branch_name))
+            }
+            _ => Err(e),
+        },
+    }
+}
+
+/// Summarize up to `max` of the commits reachable from `from` but not from
+/// `hide`, newest first.
+fn commits(repo: &Repository, from: Oid, hide: Oid, max: usize) -> Result<Vec<CommitSummary>> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+    revwalk.push(from)?;
+    revwalk.hide(hide)?;
+
+    let mut summaries = Vec::new();
+    for oid in revwalk.take(max) {
+        let commit = repo.find_commit(oid?)?;
+        let author = commit.author();
+        summaries.push(CommitSummary::new(
+            &commit.id().to_string(),
+            author.name().unwrap_or_default(),
+            author.email().unwrap_or_default(),
+            author.when().seconds(),
+            commit.summary().unwrap_or_default(),
+        ));
+    }
+    Ok(summaries)
+}
+
+/// The changes between the trees of two commits, with up to `max_paths` of
+/// the most changed paths.
+fn diffstat(repo: &Repository, from: Oid, to: Oid, max_paths: usize) -> Result<DiffStat> {
+    let from_tree = repo.find_commit(from)?.tree()?;
+    let to_tree = repo.find_commit(to)?.tree()?;
+    let diff = repo.diff_tree_to_tree(Some(&from_tree), Some(&to_tree), None)?;
+
+    let mut paths = Vec::new();
+    for (idx, delta) in diff.deltas().enumerate() {
+        let path = delta
+            .new_file()
+            .path()
+            .or_else(|| delta.old_file().path())
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let (insertions, deletions) = match Patch::from_diff(&diff, idx)? {
+            Some(patch) => {
+                let (_, insertions, deletions) = patch.line_stats()?;
+                (insertions, deletions)
+            }
+            None => (0, 0),
+        };
+        paths.push(PathStat::new(&path, insertions, deletions));
     }
+    Ok(DiffStat::new(paths, max_paths))
 }
 
 /// Get the OID for the latest commit in the given spec.
@@ -13,6 +13,8 @@ use std::cell::RefCell;
 use std::cmp::Ordering;
 use std::convert::TryFrom;
 use std::rc::Rc;
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::Arc;
 use std::time::Instant;
 use term;
 
@@ -42,6 +44,9 @@ pub struct CallbackOutput {
     #[get = "pub"]
     #[set = "pub"]
     state: CloneState,
+    /// Set to cancel the transfer.
+    #[set = "pub"]
+    cancelled: Arc<AtomicBool>,
 }
 
 impl Default for CallbackOutput {
@@ -51,6 +56,7 @@ impl Default for CallbackOutput {
             sideband: String::new(),
             progress: String::new(),
             state: CloneState::Receiving,
+            cancelled: Arc::new(AtomicBool::new(false)),
         }
     }
 }
@@ -83,6 +89,9 @@ pub fn check_creds(
 
 /// Side band remote callback.
 pub fn sideband(output: &mut CallbackOutput, text: &[u8]) -> bool {
+    if output.cancelled.load(atomic::Ordering::SeqCst) {
+        return false;
+    }
     *output.sideband_mut() = String::from_utf8_lossy(text).into_owned();
     true
 }
@@ -202,6 +211,9 @@ fn bytes_to_rate(bytes_pre: usize, start: &Instant) -> Result<String> {
 
 /// Progress remote callback.
 pub fn progress(output: &mut CallbackOutput, progress: &Progress) -> bool {
+    if output.cancelled.load(atomic::Ordering::SeqCst) {
+        return false;
+    }
     let received_objects = progress.received_objects();
     let total_objects = progress.total_objects();
 
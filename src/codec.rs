@@ -0,0 +1,223 @@
+// Copyright (c) 2017 repomons developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `repomons` wire framing.
+//!
+//! On connect the server writes a preamble of the `MAGIC` bytes followed by
+//! the protocol `VERSION`.  Every frame after that is either a 4 byte
+//! big-endian payload length followed by the payload itself, or, for the
+//! JSON lines encoding, the payload followed by a newline.
+use bytes::{BufMut, BytesMut};
+use error::{Error, Result};
+use tokio_codec::{Decoder, Encoder};
+
+/// The magic bytes that start every connection.
+pub const MAGIC: &[u8; 4] = b"RPMN";
+/// The wire protocol version.
+pub const VERSION: u8 = 10;
+/// The oldest wire protocol version still served to clients.
+pub const MIN_VERSION: u8 = 6;
+/// The length of the frame header.
+const HEADER_LEN: usize = 4;
+/// The largest payload we will encode or decode (16 MiB).
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Generate the connection preamble.
+pub fn preamble() -> Vec<u8> {
+    let mut preamble = Vec::with_capacity(MAGIC.len() + 1);
+    preamble.extend_from_slice(MAGIC);
+    preamble.push(VERSION);
+    preamble
+}
+
+/// Length-prefixed frame codec.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameCodec;
+
+impl Decoder for FrameCodec {
+    type Item = Vec<u8>;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Vec<u8>>> {
+        if src.len() < HEADER_LEN {
+            return Ok(None);
+        }
+
+        let len = src[..HEADER_LEN]
+            .iter()
+            .fold(0, |acc, byte| (acc << 8) | usize::from(*byte));
+
+        if len > MAX_FRAME_LEN {
+            return Err(format!("frame length {} exceeds the maximum", len).into());
+        }
+
+        if src.len() < HEADER_LEN + len {
+            src.reserve(HEADER_LEN + len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(HEADER_LEN);
+        Ok(Some(src.split_to(len).to_vec()))
+    }
+}
+
+impl Encoder for FrameCodec {
+    type Item = Vec<u8>;
+    type Error = Error;
+
+    fn encode(&mut self, item: Vec<u8>, dst: &mut BytesMut) -> Result<()> {
+        if item.len() > MAX_FRAME_LEN {
+            return Err(format!("frame length {} exceeds the maximum", item.len()).into());
+        }
+
+        dst.reserve(HEADER_LEN + item.len());
+        dst.put_u32_be(item.len() as u32);
+        dst.put_slice(&item);
+        Ok(())
+    }
+}
+
+/// Newline-delimited frame codec.  Blank lines are skipped.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LineCodec;
+
+impl Decoder for LineCodec {
+    type Item = Vec<u8>;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Vec<u8>>> {
+        while let Some(pos) = src.iter().position(|byte| *byte == b'\n') {
+            let mut line = src.split_to(pos + 1);
+            let len = line.len() - 1;
+            line.truncate(len);
+            if line.last() == Some(&b'\r') {
+                line.truncate(len - 1);
+            }
+            if !line.is_empty() {
+                return Ok(Some(line.to_vec()));
+            }
+        }
+
+        if src.len() > MAX_FRAME_LEN {
+            return Err(format!("line length {} exceeds the maximum", src.len()).into());
+        }
+        Ok(None)
+    }
+}
+
+impl Encoder for LineCodec {
+    type Item = Vec<u8>;
+    type Error = Error;
+
+    fn encode(&mut self, item: Vec<u8>, dst: &mut BytesMut) -> Result<()> {
+        dst.reserve(item.len() + 1);
+        dst.put_slice(&item);
+        dst.put_u8(b'\n');
+        Ok(())
+    }
+}
+
+/// The framing used by a connection, which depends on its encoding.
+#[derive(Clone, Copy, Debug)]
+pub enum Codec {
+    /// Length-prefixed frames.
+    Frame(FrameCodec),
+    /// Newline-delimited frames.
+    Line(LineCodec),
+}
+
+impl Decoder for Codec {
+    type Item = Vec<u8>;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Vec<u8>>> {
+        match *self {
+            Codec::Frame(ref mut codec) => codec.decode(src),
+            Codec::Line(ref mut codec) => codec.decode(src),
+        }
+    }
+}
+
+impl Encoder for Codec {
+    type Item = Vec<u8>;
+    type Error = Error;
+
+    fn encode(&mut self, item: Vec<u8>, dst: &mut BytesMut) -> Result<()> {
+        match *self {
+            Codec::Frame(ref mut codec) => codec.encode(item, dst),
+            Codec::Line(ref mut codec) => codec.encode(item, dst),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FrameCodec, LineCodec};
+    use bytes::BytesMut;
+    use tokio_codec::{Decoder, Encoder};
+
+    #[test]
+    fn preamble() {
+        assert_eq!(
+            super::preamble(),
+            vec![b'R', b'P', b'M', b'N', super::VERSION]
+        );
+    }
+
+    #[test]
+    fn encode() {
+        let mut buf = BytesMut::new();
+        FrameCodec
+            .encode(vec![1, 2, 3], &mut buf)
+            .expect("unable to encode frame");
+        assert_eq!(&buf[..], &[0, 0, 0, 3, 1, 2, 3]);
+    }
+
+    #[test]
+    fn decode_multiple() {
+        let mut buf = BytesMut::from(vec![0, 0, 0, 2, 1, 2, 0, 0, 0, 1, 3]);
+        let mut codec = FrameCodec;
+        assert_eq!(codec.decode(&mut buf).expect(""), Some(vec![1, 2]));
+        assert_eq!(codec.decode(&mut buf).expect(""), Some(vec![3]));
+        assert_eq!(codec.decode(&mut buf).expect(""), None);
+    }
+
+    #[test]
+    fn decode_partial() {
+        let mut buf = BytesMut::from(vec![0, 0, 0]);
+        let mut codec = FrameCodec;
+        assert_eq!(codec.decode(&mut buf).expect(""), None);
+        buf.extend_from_slice(&[4, 1, 2]);
+        assert_eq!(codec.decode(&mut buf).expect(""), None);
+        buf.extend_from_slice(&[3, 4]);
+        assert_eq!(codec.decode(&mut buf).expect(""), Some(vec![1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn decode_too_large() {
+        let mut buf = BytesMut::from(vec![0xff, 0xff, 0xff, 0xff]);
+        assert!(FrameCodec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn lines() {
+        let mut buf = BytesMut::new();
+        LineCodec
+            .encode(b"{}".to_vec(), &mut buf)
+            .expect("unable to encode line");
+        assert_eq!(&buf[..], b"{}\n");
+
+        let mut buf = BytesMut::from(&b"[1]\r\n\n[2]\n[3"[..]);
+        let mut codec = LineCodec;
+        assert_eq!(codec.decode(&mut buf).expect(""), Some(b"[1]".to_vec()));
+        assert_eq!(codec.decode(&mut buf).expect(""), Some(b"[2]".to_vec()));
+        assert_eq!(codec.decode(&mut buf).expect(""), None);
+        buf.extend_from_slice(b"]\n");
+        assert_eq!(codec.decode(&mut buf).expect(""), Some(b"[3]".to_vec()));
+    }
+}
@@ -0,0 +1,404 @@
+// Copyright (c) 2017 repomons developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `repomons` server configuration.
+//!
+//! The server settings live in the `[server]` table of the same TOML file as
+//! the `repomon` repository configuration, which ignores them, as it does the
+//! `repomons` specific repository settings in the `[repos.<name>]` tables.
+use error::Result;
+use queue::{self, Overflow, Receiver, Sender};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use toml;
+
+/// The `repomons` specific configuration.
+#[derive(Clone, Debug, Default, Deserialize, Getters, Setters)]
+pub struct Config {
+    /// The server settings.
+    #[serde(default)]
+    #[get = "pub"]
+    #[set = "pub"]
+    server: ServerConfig,
+    /// The `repomons` specific repository settings, by repository name.
+    #[serde(default)]
+    #[get = "pub"]
+    repos: BTreeMap<String, RepoConfig>,
+}
+
+impl Config {
+    /// Parse the configuration from a TOML string.
+    pub fn from_toml(toml: &str) -> Result<Self> {
+        Ok(toml::from_str(toml)?)
+    }
+}
+
+/// The `repomons` specific repository settings.
+#[derive(Clone, Debug, Default, Deserialize, Getters)]
+pub struct RepoConfig {
+    /// The path of the repository, instead of its name under the base
+    /// directory.  A relative path is relative to the base directory.
+    #[serde(default)]
+    #[get = "pub"]
+    path: Option<PathBuf>,
+    /// The remote the other remotes of the repository mirror.  Each branch
+    /// on the other remotes is also compared against the same branch on it.
+    #[serde(default)]
+    #[get = "pub"]
+    mirror_source: Option<String>,
+}
+
+/// The server settings.
+#[derive(Clone, Debug, Deserialize, Getters, Setters)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// The TLS settings for the monitor listener.
+    #[get = "pub"]
+    #[set = "pub"]
+    tls: Option<TlsConfig>,
+    /// The client tokens.  Clients must authenticate with one of these when
+    /// any are configured.
+    #[get = "pub"]
+    tokens: Vec<TokenConfig>,
+    /// The number of seconds a client has to authenticate before it is disconnected.
+    auth_timeout: u64,
+    /// The client queue settings.
+    #[get = "pub"]
+    queue: QueueConfig,
+    /// The heartbeat settings.
+    #[get = "pub"]
+    heartbeat: HeartbeatConfig,
+    /// The event detail settings.
+    #[get = "pub"]
+    events: EventConfig,
+    /// The number of seconds fetches in flight are given to finish, and
+    /// clients are given to receive their queued frames, on shutdown.
+    shutdown_timeout: u64,
+    /// The number of branch checks run at the same time.
+    #[get = "pub"]
+    workers: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            tls: None,
+            tokens: Vec::new(),
+            auth_timeout: 10,
+            queue: Default::default(),
+            heartbeat: Default::default(),
+            events: Default::default(),
+            shutdown_timeout: 10,
+            workers: 4,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// The time a client has to authenticate before it is disconnected.
+    pub fn auth_timeout(&self) -> Duration {
+        Duration::from_secs(self.auth_timeout)
+    }
+
+    /// The time fetches in flight, and then clients, are given on shutdown.
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout)
+    }
+}
+
+/// The TLS settings for the monitor listener.
+#[derive(Clone, Debug, Default, Deserialize, Getters, Setters)]
+pub struct TlsConfig {
+    /// The PEM certificate chain file.
+    #[get = "pub"]
+    #[set = "pub"]
+    cert: PathBuf,
+    /// The PEM private key file.
+    #[get = "pub"]
+    #[set = "pub"]
+    key: PathBuf,
+    /// The PEM CA file used to verify client certificates.  Clients must
+    /// present a certificate signed by one of these CAs when set.
+    #[serde(default)]
+    #[get = "pub"]
+    #[set = "pub"]
+    client_ca: Option<PathBuf>,
+}
+
+/// The client queue settings.
+#[derive(Clone, Copy, Debug, Deserialize, Getters, Setters)]
+#[serde(default)]
+pub struct QueueConfig {
+    /// The maximum number of frames queued for a client.
+    #[get = "pub"]
+    #[set = "pub"]
+    capacity: usize,
+    /// What to do when a client queue is full.
+    #[get = "pub"]
+    #[set = "pub"]
+    overflow: Overflow,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 256,
+            overflow: Default::default(),
+        }
+    }
+}
+
+impl QueueConfig {
+    /// Create a client queue with these settings.
+    pub fn channel<T>(&self) -> (Sender<T>, Receiver<T>) {
+        queue::channel(self.capacity, self.overflow)
+    }
+}
+
+/// The heartbeat settings.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default)]
+pub struct HeartbeatConfig {
+    /// The number of seconds between heartbeats, or 0 to disable them.
+    interval: u64,
+    /// The number of seconds a client that pings may stay silent before it is
+    /// dropped as dead.
+    timeout: u64,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: 30,
+            timeout: 90,
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    /// The time between heartbeats, if they are enabled.
+    pub fn interval(&self) -> Option<Duration> {
+        match self.interval {
+            0 => None,
+            interval => Some(Duration::from_secs(interval)),
+        }
+    }
+
+    /// The time a client that pings may stay silent before it is dropped.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+/// The event detail settings.
+#[derive(Clone, Copy, Debug, Deserialize, Getters)]
+#[serde(default)]
+pub struct EventConfig {
+    /// The maximum number of commit summaries in an event, in each direction
+    /// against each remote.
+    #[get = "pub"]
+    max_commits: usize,
+    /// The maximum number of paths in the diffstat of an event, against each
+    /// remote.
+    #[get = "pub"]
+    max_paths: usize,
+}
+
+impl Default for EventConfig {
+    fn default() -> Self {
+        Self {
+            max_commits: 10,
+            max_paths: 10,
+        }
+    }
+}
+
+/// A client token, and the repositories it may see.
+#[derive(Clone, Debug, Default, Deserialize, Getters, Setters)]
+pub struct TokenConfig {
+    /// The shared token.
+    #[get = "pub"]
+    #[set = "pub"]
+    token: String,
+    /// The repository name globs the token may see (all if empty).
+    #[serde(default)]
+    #[get = "pub"]
+    #[set = "pub"]
+    repos: Vec<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::Config;
+    use queue::Overflow;
+    use std::path::Path;
+    use std::time::Duration;
+
+    #[test]
+    fn no_server_table() {
+        let config = Config::from_toml(
+            r#"basedir = "/home/jozias/projects"
+
+            [[repos.ar2.remotes]]
+            name = "origin"
+            url = "jozias@jasonozias.com:repos/ar2.git"
+            "#,
+        )
+        .expect("invalid config");
+        assert!(config.server().tls().is_none());
+        assert!(config.server().tokens().is_empty());
+        assert_eq!(config.server().auth_timeout(), Duration::from_secs(10));
+        assert_eq!(config.server().shutdown_timeout(), Duration::from_secs(10));
+        assert_eq!(*config.server().workers(), 4);
+        assert_eq!(*config.server().events().max_commits(), 10);
+        assert_eq!(*config.server().events().max_paths(), 10);
+        assert!(config.repos()["ar2"].path().is_none());
+        assert_eq!(*config.server().queue().capacity(), 256);
+        assert_eq!(*config.server().queue().overflow(), Overflow::DropOldest);
+        assert_eq!(
+            config.server().heartbeat().interval(),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            config.server().heartbeat().timeout(),
+            Duration::from_secs(90)
+        );
+    }
+
+    #[test]
+    fn heartbeat() {
+        let config = Config::from_toml(
+            r#"[server.heartbeat]
+            interval = 0
+            timeout = 20
+            "#,
+        )
+        .expect("invalid config");
+        assert!(config.server().heartbeat().interval().is_none());
+        assert_eq!(
+            config.server().heartbeat().timeout(),
+            Duration::from_secs(20)
+        );
+    }
+
+    #[test]
+    fn events() {
+        let config = Config::from_toml(
+            r#"[server.events]
+            max_commits = 3
+            max_paths = 5
+            "#,
+        )
+        .expect("invalid config");
+        assert_eq!(*config.server().events().max_commits(), 3);
+        assert_eq!(*config.server().events().max_paths(), 5);
+    }
+
+    #[test]
+    fn queue() {
+        let config = Config::from_toml(
+            r#"[server.queue]
+            capacity = 16
+            overflow = "disconnect"
+            "#,
+        )
+        .expect("invalid config");
+        assert_eq!(*config.server().queue().capacity(), 16);
+        assert_eq!(*config.server().queue().overflow(), Overflow::Disconnect);
+        assert!(Config::from_toml("[server.queue]\noverflow = \"drop\"").is_err());
+    }
+
+    #[test]
+    fn tokens() {
+        let config = Config::from_toml(
+            r#"[server]
+            auth_timeout = 5
+            shutdown_timeout = 3
+            workers = 2
+
+            [[server.tokens]]
+            token = "s3cret"
+            repos = ["repomon*", "ar2"]
+
+            [[server.tokens]]
+            token = "t0ps3cret"
+            "#,
+        )
+        .expect("invalid config");
+        let tokens = config.server().tokens();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens[0].token(), "s3cret");
+        assert_eq!(tokens[0].repos(), &["repomon*", "ar2"]);
+        assert!(tokens[1].repos().is_empty());
+        assert_eq!(config.server().auth_timeout(), Duration::from_secs(5));
+        assert_eq!(config.server().shutdown_timeout(), Duration::from_secs(3));
+        assert_eq!(*config.server().workers(), 2);
+    }
+
+    #[test]
+    fn tls() {
+        let config = Config::from_toml(
+            r#"basedir = "/home/jozias/projects"
+
+            [server.tls]
+            cert = "/etc/repomons/cert.pem"
+            key = "/etc/repomons/key.pem"
+            client_ca = "/etc/repomons/ca.pem"
+            "#,
+        )
+        .expect("invalid config");
+        let tls = config.server().tls().as_ref().expect("no tls config");
+        assert_eq!(tls.cert(), Path::new("/etc/repomons/cert.pem"));
+        assert_eq!(tls.key(), Path::new("/etc/repomons/key.pem"));
+        assert_eq!(
+            tls.client_ca().as_ref().map(|ca| ca.as_path()),
+            Some(Path::new("/etc/repomons/ca.pem"))
+        );
+
+        assert!(Config::from_toml("[server.tls]\ncert = \"cert.pem\"").is_err());
+    }
+
+    #[test]
+    fn repo_paths() {
+        let config = Config::from_toml(
+            r#"basedir = "/home/jozias/projects"
+
+            [repos.ar2]
+            path = "/srv/git/ar2"
+            mirror_source = "origin"
+
+            [[repos.ar2.remotes]]
+            name = "origin"
+            url = "jozias@jasonozias.com:repos/ar2.git"
+
+            [[repos.repomon.branch]]
+            name = "master"
+            interval = "1m"
+            remotes = ["origin"]
+            "#,
+        )
+        .expect("invalid config");
+        assert_eq!(
+            config.repos()["ar2"]
+                .path()
+                .as_ref()
+                .map(|path| path.as_path()),
+            Some(Path::new("/srv/git/ar2"))
+        );
+        assert_eq!(
+            config.repos()["ar2"]
+                .mirror_source()
+                .as_ref()
+                .map(|s| s.as_str()),
+            Some("origin")
+        );
+        assert!(config.repos()["repomon"].path().is_none());
+        assert!(config.repos()["repomon"].mirror_source().is_none());
+    }
+}
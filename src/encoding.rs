@@ -0,0 +1,369 @@
+// Copyright (c) 2017 repomons developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `repomons` wire encodings.
+//!
+//! After the preamble, a client picks the encoding and protocol version of
+//! its connection by sending a hello line, e.g. `json 7\n`.  The version may
+//! be omitted by clients predating the version exchange, which are served
+//! `MIN_VERSION`.  Frames and requests are then encoded with the encoding, in
+//! the schema of the version, in both directions.
+use bincode::{deserialize, serialize, Infinite};
+use codec::{Codec, FrameCodec, LineCodec, MIN_VERSION, VERSION};
+use error::{Error, Result};
+use futures::future::{loop_fn, Loop};
+use futures::Future;
+use json;
+use protocol::{
+    Envelope, Request, ServerFrame, Versioned, ENVELOPE_VERSION, HEARTBEAT_VERSION,
+    SHUTDOWN_VERSION, STATUS_VERSION,
+};
+use rmp_serde;
+use serde::de::DeserializeOwned;
+use serde_cbor;
+use serde_json;
+use std::fmt;
+use std::str::FromStr;
+use tokio_io::io::read_exact;
+use tokio_io::AsyncRead;
+
+/// The longest hello line we will read.
+const MAX_HELLO_LEN: usize = 32;
+
+/// A wire encoding.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    /// Length-prefixed bincode, for Rust clients sharing the `repomon` types.
+    Bincode,
+    /// Newline-delimited JSON.
+    Json,
+    /// Length-prefixed CBOR.
+    Cbor,
+    /// Length-prefixed MessagePack.
+    MessagePack,
+}
+
+impl FromStr for Encoding {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "bincode" => Ok(Encoding::Bincode),
+            "json" => Ok(Encoding::Json),
+            "cbor" => Ok(Encoding::Cbor),
+            "msgpack" => Ok(Encoding::MessagePack),
+            _ => Err(format!("unknown encoding: {}", name).into()),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Encoding::Bincode => "bincode",
+            Encoding::Json => "json",
+            Encoding::Cbor => "cbor",
+            Encoding::MessagePack => "msgpack",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Encoding {
+    /// The framing used with this encoding.
+    pub fn codec(self) -> Codec {
+        match self {
+            Encoding::Json => Codec::Line(LineCodec),
+            _ => Codec::Frame(FrameCodec),
+        }
+    }
+
+    /// Encode a server frame for the given protocol version.
+    pub fn encode(self, frame: &ServerFrame, version: u8) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Bincode => Ok(serialize(&Versioned::new(version, frame), Infinite)?),
+            Encoding::Json => json::to_vec(frame, version),
+            Encoding::Cbor => json::to_cbor(frame, version),
+            Encoding::MessagePack => json::to_msgpack(frame, version),
+        }
+    }
+
+    /// Decode a client request sent with the given protocol version.
+    pub fn decode(self, payload: &[u8], version: u8) -> Result<Request> {
+        if version < ENVELOPE_VERSION {
+            return self.deserialize(payload);
+        }
+
+        let envelope: Envelope<Request> = self.deserialize(payload)?;
+        if *envelope.version() != version {
+            return Err(format!(
+                "request version {} does not match the negotiated version {}",
+                envelope.version(),
+                version
+            )
+            .into());
+        }
+        Ok(envelope.into_body())
+    }
+
+    /// Deserialize a payload with this encoding.
+    fn deserialize<T: DeserializeOwned>(self, payload: &[u8]) -> Result<T> {
+        match self {
+            Encoding::Bincode => Ok(deserialize(payload)?),
+            Encoding::Json => Ok(serde_json::from_slice(payload)?),
+            Encoding::Cbor => Ok(serde_cbor::from_slice(payload)?),
+            Encoding::MessagePack => Ok(rmp_serde::from_slice(payload)?),
+        }
+    }
+}
+
+/// The encoding and protocol version picked by a client.
+#[derive(Clone, Copy, Debug, Getters, PartialEq)]
+pub struct Hello {
+    /// The wire encoding.
+    #[get = "pub"]
+    encoding: Encoding,
+    /// The protocol version.
+    #[get = "pub"]
+    version: u8,
+}
+
+impl FromStr for Hello {
+    type Err = Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let mut words = line.split_whitespace();
+        let encoding = words.next().unwrap_or("").parse()?;
+        let version = match words.next() {
+            Some(version) => version
+                .parse()
+                .map_err(|_| format!("invalid protocol version: {}", version))?,
+            None => MIN_VERSION,
+        };
+        if words.next().is_some() {
+            return Err(format!("invalid hello: {}", line).into());
+        }
+        if !(MIN_VERSION..=VERSION).contains(&version) {
+            return Err(format!(
+                "unsupported protocol version {}, expected {} to {}",
+                version, MIN_VERSION, VERSION
+            )
+            .into());
+        }
+
+        Ok(Self { encoding, version })
+    }
+}
+
+impl Hello {
+    /// The given frame as a client of this protocol version is sent it, if
+    /// it is sent at all.
+    pub fn downgrade(&self, frame: ServerFrame) -> Option<ServerFrame> {
+        match frame {
+            ServerFrame::Heartbeat if self.version < HEARTBEAT_VERSION => None,
+            ServerFrame::Shutdown if self.version < SHUTDOWN_VERSION => None,
+            ServerFrame::StatusEvent(event) if self.version < STATUS_VERSION => {
+                Some(ServerFrame::Event(event.into_message()))
+            }
+            frame => Some(frame),
+        }
+    }
+
+    /// Encode a server frame for this connection.
+    pub fn encode(&self, frame: &ServerFrame) -> Result<Vec<u8>> {
+        self.encoding.encode(frame, self.version)
+    }
+
+    /// Decode a client request on this connection.
+    pub fn decode(&self, payload: &[u8]) -> Result<Request> {
+        self.encoding.decode(payload, self.version)
+    }
+}
+
+/// Read the hello line sent by a client.
+pub fn negotiate<R>(reader: R) -> Box<dyn Future<Item = (R, Hello), Error = Error>>
+where
+    R: AsyncRead + 'static,
+{
+    Box::new(loop_fn((reader, Vec::new()), |(reader, mut line)| {
+        read_exact(reader, [0; 1])
+            .map_err(Error::from)
+            .and_then(move |(reader, byte)| match byte[0] {
+                b'\n' => {
+                    let hello = String::from_utf8_lossy(&line).parse()?;
+                    Ok(Loop::Break((reader, hello)))
+                }
+                _ if line.len() >= MAX_HELLO_LEN => Err("hello line too long".into()),
+                byte => {
+                    line.push(byte);
+                    Ok(Loop::Continue((reader, line)))
+                }
+            })
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{negotiate, Encoding, Hello};
+    use bincode::deserialize;
+    use codec::VERSION;
+    use event::{BranchStatus, RemoteStatus, State};
+    use futures::Future;
+    use protocol::{Command, Envelope, Reply, Response, ServerFrame, StatusEvent};
+    use repomon::Message;
+    use rmp_serde;
+    use serde_cbor;
+    use serde_json::{self, Value};
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn negotiate_hello() {
+        let (mut reader, hello) = negotiate(Cursor::new(b"msgpack 7\r\nrest".to_vec()))
+            .wait()
+            .expect("negotiation failed");
+        assert_eq!(hello.encoding(), &Encoding::MessagePack);
+        assert_eq!(hello.version(), &7);
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).expect("");
+        assert_eq!(rest, "rest");
+
+        assert!(negotiate(Cursor::new(b"xml\n".to_vec())).wait().is_err());
+        assert!(negotiate(Cursor::new(vec![b'j'; 64])).wait().is_err());
+        assert_eq!("cbor".parse::<Encoding>().expect("").to_string(), "cbor");
+    }
+
+    #[test]
+    fn hello_versions() {
+        assert_eq!("json".parse::<Hello>().expect("").version(), &6);
+        assert_eq!("json 6".parse::<Hello>().expect("").version(), &6);
+        assert!("json 5".parse::<Hello>().is_err());
+        assert!(format!("json {}", VERSION + 1).parse::<Hello>().is_err());
+        assert!("json seven".parse::<Hello>().is_err());
+        assert!("json 7 8".parse::<Hello>().is_err());
+        assert!("".parse::<Hello>().is_err());
+    }
+
+    #[test]
+    fn downgrade() {
+        let old = "bincode 7".parse::<Hello>().expect("");
+        let new = "bincode 8".parse::<Hello>().expect("");
+        assert!(old.downgrade(ServerFrame::Heartbeat).is_none());
+        assert!(new.downgrade(ServerFrame::Heartbeat).is_some());
+        assert!(old.downgrade(ServerFrame::Snapshot(Vec::new())).is_some());
+        assert!(new.downgrade(ServerFrame::Shutdown).is_none());
+        assert!("bincode 9"
+            .parse::<Hello>()
+            .expect("")
+            .downgrade(ServerFrame::Shutdown)
+            .is_some());
+
+        let mut message: Message = Default::default();
+        message.set_repo("repomon".to_string());
+        let event = ServerFrame::StatusEvent(Box::new(StatusEvent::new(
+            message,
+            BranchStatus::new("repomon", "master"),
+            None,
+        )));
+        match "bincode 9"
+            .parse::<Hello>()
+            .expect("")
+            .downgrade(event.clone())
+        {
+            Some(ServerFrame::Event(message)) => assert_eq!(message.repo(), "repomon"),
+            frame => panic!("unexpected frame: {:?}", frame),
+        }
+        match "bincode 10".parse::<Hello>().expect("").downgrade(event) {
+            Some(ServerFrame::StatusEvent(event)) => {
+                assert_eq!(event.status().branch(), "master")
+            }
+            frame => panic!("unexpected frame: {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn decode() {
+        // The requests as a Python or Go client would build them.
+        let requests = vec![
+            json!({"id": 1, "command": {"Status": "repomon"}}),
+            json!({"id": 2, "command": "ListRepos"}),
+        ];
+
+        for request in &requests {
+            let enveloped = json!({"version": 7, "body": request});
+            for &(version, ref value) in &[(6, request), (7, &enveloped)] {
+                let payloads = vec![
+                    (Encoding::Json, serde_json::to_vec(value).expect("")),
+                    (Encoding::Cbor, serde_cbor::to_vec(value).expect("")),
+                    (
+                        Encoding::MessagePack,
+                        rmp_serde::to_vec_named(value).expect(""),
+                    ),
+                ];
+                for (encoding, payload) in payloads {
+                    let decoded = encoding.decode(&payload, version).expect("invalid request");
+                    assert_eq!(Value::from(*decoded.id()), request["id"]);
+                }
+            }
+        }
+
+        let decoded = Encoding::Cbor
+            .decode(&serde_cbor::to_vec(&requests[0]).expect(""), 6)
+            .expect("");
+        assert_eq!(
+            decoded.command(),
+            &Command::Status(Some("repomon".to_string()))
+        );
+
+        // A bare request, or one in another version, is rejected once enveloped.
+        let payload = serde_json::to_vec(&requests[1]).expect("");
+        assert!(Encoding::Json.decode(&payload, 7).is_err());
+        let payload = serde_json::to_vec(&json!({"version": 6, "body": requests[1]})).expect("");
+        assert!(Encoding::Json.decode(&payload, 7).is_err());
+    }
+
+    #[test]
+    fn encode() {
+        let frame = ServerFrame::Response(Response::new(1, Ok(Reply::Subscribed(2))));
+        let bare = json!({"Response": {"id": 1, "result": {"Ok": {"Subscribed": 2}}}});
+        let enveloped = json!({"version": 7, "body": bare});
+
+        for &(version, expected) in &[(6, &bare), (7, &enveloped)] {
+            let json: Value =
+                serde_json::from_slice(&Encoding::Json.encode(&frame, version).expect(""))
+                    .expect("");
+            assert_eq!(&json, expected);
+            let cbor: Value =
+                serde_cbor::from_slice(&Encoding::Cbor.encode(&frame, version).expect(""))
+                    .expect("");
+            assert_eq!(&cbor, expected);
+            let msgpack: Value =
+                rmp_serde::from_slice(&Encoding::MessagePack.encode(&frame, version).expect(""))
+                    .expect("");
+            assert_eq!(&msgpack, expected);
+        }
+    }
+
+    #[test]
+    fn encode_status_event() {
+        let mut status = BranchStatus::new("repomon", "master");
+        status.set_remotes(vec![RemoteStatus::new("origin", 0, 2)]);
+        let frame =
+            ServerFrame::StatusEvent(Box::new(StatusEvent::new(Default::default(), status, None)));
+
+        let payload = Encoding::Bincode.encode(&frame, VERSION).expect("");
+        let envelope: Envelope<ServerFrame> = deserialize(&payload).expect("");
+        match envelope.into_body() {
+            ServerFrame::StatusEvent(event) => {
+                let remote = &event.status().remotes()[0];
+                assert_eq!(remote.state(), &State::Behind);
+                assert_eq!(remote.behind(), &2);
+            }
+            frame => panic!("unexpected frame: {:?}", frame),
+        }
+    }
+}
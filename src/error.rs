@@ -10,9 +10,17 @@
 error_chain! {
     foreign_links {
         AddrParse(::std::net::AddrParseError);
+        Bincode(::bincode::Error);
+        Cbor(::serde_cbor::Error);
         Git2(::git2::Error);
+        Glob(::glob::PatternError);
         Io(::std::io::Error);
+        Json(::serde_json::Error);
+        MessagePackDecode(::rmp_serde::decode::Error);
+        MessagePackEncode(::rmp_serde::encode::Error);
+        Openssl(::openssl::error::ErrorStack);
         Repomon(::repomon::Error);
+        TomlDe(::toml::de::Error);
         TryFromInt(::std::num::TryFromIntError);
     }
 }
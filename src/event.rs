@@ -0,0 +1,609 @@
+// Copyright (c) 2017 repomons developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `repomons` monitor events.
+use chrono::{DateTime, TimeZone, Utc};
+use repomon::{Branch, Category, Message, Remote};
+use std::collections::{BTreeMap, BTreeSet};
+use uuid::Uuid;
+
+/// An update sent from the branch checks to the server.
+#[derive(Clone, Debug)]
+pub enum Update {
+    /// The result of checking a branch.
+    Event(Event),
+    /// The repository, branch pattern and remote branches the pattern
+    /// matched in the last check.
+    Matched(String, String, BTreeSet<String>),
+    /// The repository, remote and the branches on the remote, with their
+    /// OIDs, found in the last check.
+    Refs(String, String, BTreeMap<String, String>),
+}
+
+/// The result of a single monitor check, sent from a monitor to the server.
+#[derive(Clone, Debug, Getters, Setters)]
+pub struct Event {
+    /// The sequence id assigned when the server publishes the event.
+    #[get = "pub"]
+    #[set = "pub"]
+    id: u64,
+    /// The message sent to clients.
+    #[get = "pub"]
+    message: Message,
+    /// The structured status behind the message.
+    #[get = "pub"]
+    status: BranchStatus,
+    /// The change to the branches on a remote this event reports, if any.
+    /// These events leave the latest status of the branch alone.
+    #[get = "pub"]
+    change: Option<RefChange>,
+}
+
+impl Event {
+    /// Create a new event.
+    pub fn new(message: Message, status: BranchStatus) -> Self {
+        Self {
+            id: 0,
+            message,
+            status,
+            change: None,
+        }
+    }
+}
+
+/// A change to the branches on a remote between two checks.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum RefChange {
+    /// A branch was created at the OID.
+    Created(String, String),
+    /// A branch at the OID was deleted.
+    Deleted(String, String),
+    /// A branch at the OID was renamed from the first name to the second.
+    Renamed(String, String, String),
+}
+
+impl RefChange {
+    /// The changes between two sets of branches, with their OIDs.  A branch
+    /// deleted while another is created at the same OID was renamed.
+    /// Branches that only moved are not changes.
+    pub fn diff(old: &BTreeMap<String, String>, new: &BTreeMap<String, String>) -> Vec<Self> {
+        let mut created: Vec<(&String, &String)> = new
+            .iter()
+            .filter(|(branch, _)| !old.contains_key(*branch))
+            .collect();
+
+        let mut changes = Vec::new();
+        for (branch, oid) in old.iter().filter(|(branch, _)| !new.contains_key(*branch)) {
+            match created.iter().position(|(_, new_oid)| *new_oid == oid) {
+                Some(idx) => {
+                    let (new_branch, _) = created.remove(idx);
+                    changes.push(RefChange::Renamed(
+                        branch.clone(),
+                        new_branch.clone(),
+                        oid.clone(),
+                    ));
+                }
+                None => changes.push(RefChange::Deleted(branch.clone(), oid.clone())),
+            }
+        }
+        changes.extend(
+            created
+                .into_iter()
+                .map(|(branch, oid)| RefChange::Created(branch.clone(), oid.clone())),
+        );
+        changes
+    }
+
+    /// The branch the change is reported for, the new name of a renamed branch.
+    pub fn branch(&self) -> &str {
+        match *self {
+            RefChange::Created(ref branch, _)
+            | RefChange::Deleted(ref branch, _)
+            | RefChange::Renamed(_, ref branch, _) => branch,
+        }
+    }
+
+    /// Describe the change on the given remote.
+    pub fn message(&self, remote: &str) -> String {
+        match *self {
+            RefChange::Created(ref branch, ref oid) => {
+                format!("Branch '{}' was created on '{}' at {}", branch, remote, oid)
+            }
+            RefChange::Deleted(ref branch, ref oid) => {
+                format!(
+                    "Branch '{}' was deleted from '{}' at {}",
+                    branch, remote, oid
+                )
+            }
+            RefChange::Renamed(ref from, ref to, ref oid) => format!(
+                "Branch '{}' was renamed to '{}' on '{}' at {}",
+                from, to, remote, oid
+            ),
+        }
+    }
+
+    /// The event reporting the change on a repository remote.
+    pub fn event(&self, repo: &str, remote: &str) -> Event {
+        let mut branch: Branch = Default::default();
+        branch.set_name(self.branch().to_string());
+        let mut remote_ref: Remote = Default::default();
+        remote_ref.set_name(format!("{}/{}", remote, self.branch()));
+        let mut remotes = BTreeMap::new();
+        remotes.insert(remote_ref, self.message(remote));
+        let mut messages = BTreeMap::new();
+        messages.insert(branch, remotes);
+
+        let mut message: Message = Default::default();
+        message.set_uuid(Uuid::new_v4());
+        message.set_category(Category::Info);
+        message.set_repo(repo.to_string());
+        message.set_messages(messages);
+
+        let mut event = Event::new(message, BranchStatus::new(repo, self.branch()));
+        event.change = Some(self.clone());
+        event
+    }
+}
+
+/// The status of a branch against each of its monitored remotes.
+#[derive(Clone, Debug, Deserialize, Getters, Serialize, Setters)]
+pub struct BranchStatus {
+    /// The repository name.
+    #[get = "pub"]
+    repo: String,
+    /// The branch name.
+    #[get = "pub"]
+    branch: String,
+    /// When the check finished.
+    #[get = "pub"]
+    #[set = "pub"]
+    checked: DateTime<Utc>,
+    /// The status against each remote.
+    #[get = "pub"]
+    #[set = "pub"]
+    remotes: Vec<RemoteStatus>,
+    /// The status of each mirror against its source, when the repository
+    /// has a mirror source.
+    #[get = "pub"]
+    #[set = "pub"]
+    mirrors: Vec<MirrorStatus>,
+}
+
+impl BranchStatus {
+    /// Create a new, empty, status for the given repository branch.
+    pub fn new(repo: &str, branch: &str) -> Self {
+        Self {
+            repo: repo.to_string(),
+            branch: branch.to_string(),
+            checked: Utc::now(),
+            remotes: Vec::new(),
+            mirrors: Vec::new(),
+        }
+    }
+
+    /// The category of the branch across every remote, the first of
+    /// `Behind`, `Ahead` and `UpToDate` that any remote is in, so it doesn't
+    /// depend on the order the remotes were checked in.  A lagging mirror is
+    /// also `Behind`.  A branch with no remotes is `Info`.
+    pub fn category(&self) -> Category {
+        if self.mirrors.iter().any(|mirror| mirror.behind > 0) {
+            return Category::Behind;
+        }
+        [Category::Behind, Category::Ahead, Category::UpToDate]
+            .iter()
+            .find(|category| {
+                self.remotes
+                    .iter()
+                    .any(|remote| remote.category == **category)
+            })
+            .cloned()
+            .unwrap_or(Category::Info)
+    }
+}
+
+/// The state of a branch against a remote.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum State {
+    /// The branch and the remote are at the same commit.
+    UpToDate,
+    /// The branch has commits the remote doesn't.
+    Ahead,
+    /// The remote has commits the branch doesn't.
+    Behind,
+    /// Both have commits the other doesn't.
+    Diverged,
+}
+
+impl State {
+    /// The state of a branch from its ahead/behind counts.
+    pub fn new(ahead: usize, behind: usize) -> Self {
+        match (ahead > 0, behind > 0) {
+            (true, true) => State::Diverged,
+            (false, true) => State::Behind,
+            (true, false) => State::Ahead,
+            (false, false) => State::UpToDate,
+        }
+    }
+}
+
+/// The status of a branch against a single remote.
+#[derive(Clone, Debug, Deserialize, Getters, Serialize, Setters)]
+pub struct RemoteStatus {
+    /// The remote name.
+    #[get = "pub"]
+    remote: String,
+    /// The number of local commits not on the remote.
+    #[get = "pub"]
+    ahead: usize,
+    /// The number of remote commits not on the local branch.
+    #[get = "pub"]
+    behind: usize,
+    /// The category of the status.  A diverged branch is `Behind`, as that
+    /// is what needs attention first.
+    #[get = "pub"]
+    category: Category,
+    /// The state of the branch against the remote.
+    #[get = "pub"]
+    state: State,
+    /// The merge base of the branch and the remote, if they have one.
+    #[get = "pub"]
+    #[set = "pub"]
+    merge_base: Option<String>,
+    /// The newest remote commits not on the local branch, up to the
+    /// configured maximum.
+    #[get = "pub"]
+    #[set = "pub"]
+    incoming: Vec<CommitSummary>,
+    /// The newest local commits not on the remote, up to the configured
+    /// maximum.
+    #[get = "pub"]
+    #[set = "pub"]
+    outgoing: Vec<CommitSummary>,
+    /// The changes on the remote since the merge base, or since the local
+    /// branch when there is none.
+    #[get = "pub"]
+    #[set = "pub"]
+    diffstat: DiffStat,
+}
+
+impl RemoteStatus {
+    /// Create a new remote status from the ahead/behind counts.
+    pub fn new(remote: &str, ahead: usize, behind: usize) -> Self {
+        let state = State::new(ahead, behind);
+        let category = match state {
+            State::Diverged | State::Behind => Category::Behind,
+            State::Ahead => Category::Ahead,
+            State::UpToDate => Category::UpToDate,
+        };
+
+        Self {
+            remote: remote.to_string(),
+            ahead,
+            behind,
+            category,
+            state,
+            merge_base: None,
+            incoming: Vec::new(),
+            outgoing: Vec::new(),
+            diffstat: Default::default(),
+        }
+    }
+}
+
+/// The status of the branch on a mirror against the same branch on the
+/// mirror source.
+#[derive(Clone, Debug, Deserialize, Getters, Serialize)]
+pub struct MirrorStatus {
+    /// The mirror remote name.
+    #[get = "pub"]
+    mirror: String,
+    /// The source remote name.
+    #[get = "pub"]
+    source: String,
+    /// The number of mirror commits not on the source.
+    #[get = "pub"]
+    ahead: usize,
+    /// The number of source commits not on the mirror.
+    #[get = "pub"]
+    behind: usize,
+    /// The state of the mirror against the source.
+    #[get = "pub"]
+    state: State,
+}
+
+impl MirrorStatus {
+    /// Create a new mirror status from the ahead/behind counts.
+    pub fn new(mirror: &str, source: &str, ahead: usize, behind: usize) -> Self {
+        Self {
+            mirror: mirror.to_string(),
+            source: source.to_string(),
+            ahead,
+            behind,
+            state: State::new(ahead, behind),
+        }
+    }
+
+    /// Describe the status of the given branch on the mirror.
+    pub fn message(&self, branch: &str) -> String {
+        let mirror = format!("{}/{}", self.mirror, branch);
+        let source = format!("{}/{}", self.source, branch);
+        match self.state {
+            State::UpToDate => format!("'{}' is in sync with '{}'", mirror, source),
+            State::Ahead => format!(
+                "'{}' is ahead of '{}' by {} commit(s)",
+                mirror, source, self.ahead
+            ),
+            State::Behind => format!(
+                "'{}' is behind '{}' by {} commit(s)",
+                mirror, source, self.behind
+            ),
+            State::Diverged => format!(
+                "'{}' and '{}' have diverged, and have {} and {} different commit(s) each, respectively",
+                mirror, source, self.ahead, self.behind
+            ),
+        }
+    }
+}
+
+/// The scale of the changes between two commits.
+#[derive(Clone, Debug, Default, Deserialize, Getters, Serialize)]
+pub struct DiffStat {
+    /// The number of files changed.
+    #[get = "pub"]
+    files: usize,
+    /// The number of lines inserted.
+    #[get = "pub"]
+    insertions: usize,
+    /// The number of lines deleted.
+    #[get = "pub"]
+    deletions: usize,
+    /// The most changed paths, up to the configured maximum.
+    #[get = "pub"]
+    paths: Vec<PathStat>,
+}
+
+impl DiffStat {
+    /// Total the changes to the given paths, keeping the `max_paths` paths
+    /// with the most lines changed.
+    pub fn new(mut paths: Vec<PathStat>, max_paths: usize) -> Self {
+        let files = paths.len();
+        let insertions = paths.iter().map(|path| path.insertions).sum();
+        let deletions = paths.iter().map(|path| path.deletions).sum();
+
+        paths.sort_by(|a, b| {
+            (b.insertions + b.deletions)
+                .cmp(&(a.insertions + a.deletions))
+                .then_with(|| a.path.cmp(&b.path))
+        });
+        paths.truncate(max_paths);
+
+        Self {
+            files,
+            insertions,
+            deletions,
+            paths,
+        }
+    }
+}
+
+/// The changes to a single path.
+#[derive(Clone, Debug, Deserialize, Getters, Serialize)]
+pub struct PathStat {
+    /// The path.
+    #[get = "pub"]
+    path: String,
+    /// The number of lines inserted.
+    #[get = "pub"]
+    insertions: usize,
+    /// The number of lines deleted.
+    #[get = "pub"]
+    deletions: usize,
+}
+
+impl PathStat {
+    /// Create a new path stat.
+    pub fn new(path: &str, insertions: usize, deletions: usize) -> Self {
+        Self {
+            path: path.to_string(),
+            insertions,
+            deletions,
+        }
+    }
+}
+
+/// A summary of a single commit.
+#[derive(Clone, Debug, Deserialize, Getters, Serialize)]
+pub struct CommitSummary {
+    /// The commit id.
+    #[get = "pub"]
+    oid: String,
+    /// The author name.
+    #[get = "pub"]
+    author: String,
+    /// The author email.
+    #[get = "pub"]
+    email: String,
+    /// When the commit was authored.
+    #[get = "pub"]
+    time: DateTime<Utc>,
+    /// The first line of the commit message.
+    #[get = "pub"]
+    subject: String,
+}
+
+impl CommitSummary {
+    /// Create a new commit summary, authored at the given Unix time.  A time
+    /// out of range, which a remote is free to push, is read as the epoch.
+    pub fn new(oid: &str, author: &str, email: &str, time: i64, subject: &str) -> Self {
+        Self {
+            oid: oid.to_string(),
+            author: author.to_string(),
+            email: email.to_string(),
+            time: Utc
+                .timestamp_opt(time, 0)
+                .single()
+                .unwrap_or_else(|| Utc.timestamp(0, 0)),
+            subject: subject.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        BranchStatus, CommitSummary, DiffStat, MirrorStatus, PathStat, RefChange, RemoteStatus,
+        State,
+    };
+    use repomon::Category;
+    use serde_json;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn category() {
+        assert_eq!(
+            RemoteStatus::new("origin", 0, 0).category(),
+            &Category::UpToDate
+        );
+        assert_eq!(
+            RemoteStatus::new("origin", 1, 0).category(),
+            &Category::Ahead
+        );
+        assert_eq!(
+            RemoteStatus::new("origin", 0, 1).category(),
+            &Category::Behind
+        );
+        assert_eq!(
+            RemoteStatus::new("origin", 1, 1).category(),
+            &Category::Behind
+        );
+
+        // The branch is as far behind as its furthest behind remote, whatever
+        // order the remotes are in.
+        let mut status = BranchStatus::new("repomon", "master");
+        assert_eq!(status.category(), Category::Info);
+        status.set_remotes(vec![
+            RemoteStatus::new("origin", 0, 0),
+            RemoteStatus::new("gh", 1, 0),
+        ]);
+        assert_eq!(status.category(), Category::Ahead);
+        status.set_remotes(vec![
+            RemoteStatus::new("gh", 2, 1),
+            RemoteStatus::new("origin", 1, 0),
+        ]);
+        assert_eq!(status.category(), Category::Behind);
+        status.set_remotes(vec![RemoteStatus::new("origin", 0, 0)]);
+        status.set_mirrors(vec![MirrorStatus::new("gh", "origin", 0, 1)]);
+        assert_eq!(status.category(), Category::Behind);
+    }
+
+    #[test]
+    fn state() {
+        assert_eq!(RemoteStatus::new("origin", 0, 0).state(), &State::UpToDate);
+        assert_eq!(RemoteStatus::new("origin", 1, 0).state(), &State::Ahead);
+        assert_eq!(RemoteStatus::new("origin", 0, 1).state(), &State::Behind);
+        assert_eq!(RemoteStatus::new("origin", 3, 5).state(), &State::Diverged);
+    }
+
+    #[test]
+    fn commit_summary() {
+        let commit = CommitSummary::new(
+            "37074407c2b984ef297d58981504458fbe91b930",
+            "Jason Ozias",
+            "jason.g.ozias@gmail.com",
+            1_500_000_000,
+            "Add the status API",
+        );
+        assert_eq!(
+            serde_json::to_string(&commit).expect(""),
+            r#"{"oid":"37074407c2b984ef297d58981504458fbe91b930","author":"Jason Ozias","email":"jason.g.ozias@gmail.com","time":"2017-07-14T02:40:00Z","subject":"Add the status API"}"#
+        );
+
+        let commit = CommitSummary::new("a", "b", "c", i64::MAX, "d");
+        assert_eq!(commit.time().timestamp(), 0);
+    }
+
+    #[test]
+    fn diffstat() {
+        let diffstat = DiffStat::new(
+            vec![
+                PathStat::new("README.md", 1, 0),
+                PathStat::new("src/branch.rs", 40, 12),
+                PathStat::new("src/event.rs", 2, 2),
+                PathStat::new("src/config.rs", 3, 1),
+            ],
+            2,
+        );
+        assert_eq!(*diffstat.files(), 4);
+        assert_eq!(*diffstat.insertions(), 46);
+        assert_eq!(*diffstat.deletions(), 15);
+        assert_eq!(
+            diffstat
+                .paths()
+                .iter()
+                .map(|path| path.path().as_str())
+                .collect::<Vec<_>>(),
+            vec!["src/branch.rs", "src/config.rs"]
+        );
+    }
+
+    #[test]
+    fn mirror_message() {
+        assert_eq!(
+            MirrorStatus::new("gh", "origin", 0, 0).message("master"),
+            "'gh/master' is in sync with 'origin/master'"
+        );
+        assert_eq!(
+            MirrorStatus::new("gh", "origin", 0, 3).message("master"),
+            "'gh/master' is behind 'origin/master' by 3 commit(s)"
+        );
+        assert_eq!(
+            MirrorStatus::new("bb", "origin", 2, 0).message("master"),
+            "'bb/master' is ahead of 'origin/master' by 2 commit(s)"
+        );
+        let diverged = MirrorStatus::new("gh", "origin", 2, 3);
+        assert_eq!(diverged.state(), &State::Diverged);
+        assert_eq!(
+            diverged.message("master"),
+            "'gh/master' and 'origin/master' have diverged, and have 2 and 3 different commit(s) each, respectively"
+        );
+    }
+
+    #[test]
+    fn ref_changes() {
+        let refs = |refs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            refs.iter()
+                .map(|&(branch, oid)| (branch.to_string(), oid.to_string()))
+                .collect()
+        };
+        let old = refs(&[("master", "a"), ("release/1.0", "b"), ("topic", "c")]);
+        let new = refs(&[("master", "d"), ("release/1.1", "e"), ("feature", "c")]);
+
+        assert!(RefChange::diff(&old, &old).is_empty());
+        assert_eq!(
+            RefChange::diff(&old, &new),
+            vec![
+                RefChange::Deleted("release/1.0".to_string(), "b".to_string()),
+                RefChange::Renamed("topic".to_string(), "feature".to_string(), "c".to_string()),
+                RefChange::Created("release/1.1".to_string(), "e".to_string()),
+            ]
+        );
+
+        let renamed =
+            RefChange::Renamed("topic".to_string(), "feature".to_string(), "c".to_string());
+        assert_eq!(renamed.branch(), "feature");
+        assert_eq!(
+            renamed.message("origin"),
+            "Branch 'topic' was renamed to 'feature' on 'origin' at c"
+        );
+
+        let event = renamed.event("repomon", "origin");
+        assert_eq!(event.message().category(), &Category::Info);
+        assert_eq!(event.status().branch(), "feature");
+        assert_eq!(event.change(), &Some(renamed));
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright (c) 2017 repomons developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `repomons` subscription filters.
+use error::{Error, Result};
+use glob::Pattern;
+use protocol::Subscription;
+use repomon::{Category, Message};
+use std::convert::TryFrom;
+
+/// A compiled client subscription.
+///
+/// Every non-empty criteria must match for a message to pass the filter.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    /// The repository name patterns.
+    repos: Vec<Pattern>,
+    /// The branch names.
+    branches: Vec<String>,
+    /// The message categories.
+    categories: Vec<Category>,
+}
+
+impl TryFrom<&Subscription> for Filter {
+    type Error = Error;
+
+    fn try_from(subscription: &Subscription) -> Result<Self> {
+        let mut repos = Vec::new();
+        for repo in subscription.repos() {
+            repos.push(Pattern::new(repo)?);
+        }
+
+        Ok(Self {
+            repos,
+            branches: subscription.branches().clone(),
+            categories: subscription.categories().clone(),
+        })
+    }
+}
+
+impl Filter {
+    /// Does the given message pass this filter?
+    pub fn matches(&self, message: &Message) -> bool {
+        let repo_match = self.matches_repo(message.repo());
+        let branch_match = self.branches.is_empty()
+            || message
+                .messages()
+                .keys()
+                .any(|branch| self.branches.contains(branch.name()));
+        let category_match =
+            self.categories.is_empty() || self.categories.contains(message.category());
+
+        repo_match && branch_match && category_match
+    }
+
+    /// Does the given repository name pass the repository patterns of this filter?
+    pub fn matches_repo(&self, repo: &str) -> bool {
+        self.repos.is_empty() || self.repos.iter().any(|pattern| pattern.matches(repo))
+    }
+
+    /// Does every repository pass the repository patterns of this filter?
+    pub fn matches_all_repos(&self) -> bool {
+        self.repos.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Filter;
+    use protocol::Subscription;
+    use repomon::{Branch, Category, Message};
+    use std::collections::BTreeMap;
+    use std::convert::TryFrom;
+
+    fn message(repo: &str, branch: &str, category: Category) -> Message {
+        let mut branch_key: Branch = Default::default();
+        branch_key.set_name(branch.to_string());
+        let mut messages = BTreeMap::new();
+        messages.insert(branch_key, BTreeMap::new());
+
+        let mut message: Message = Default::default();
+        message.set_repo(repo.to_string());
+        message.set_category(category);
+        message.set_messages(messages);
+        message
+    }
+
+    #[test]
+    fn empty_matches_all() {
+        let filter: Filter = Default::default();
+        assert!(filter.matches(&message("repomon", "master", Category::UpToDate)));
+    }
+
+    #[test]
+    fn repo_globs() {
+        let mut subscription: Subscription = Default::default();
+        subscription.set_repos(vec!["repomon*".to_string(), "ar2".to_string()]);
+        let filter = Filter::try_from(&subscription).expect("invalid filter");
+        assert!(filter.matches(&message("repomon", "master", Category::Behind)));
+        assert!(filter.matches(&message("repomons", "master", Category::Behind)));
+        assert!(filter.matches(&message("ar2", "master", Category::Behind)));
+        assert!(!filter.matches(&message("aoc", "master", Category::Behind)));
+    }
+
+    #[test]
+    fn branches_and_categories() {
+        let mut subscription: Subscription = Default::default();
+        subscription.set_branches(vec!["master".to_string()]);
+        subscription.set_categories(vec![Category::Behind, Category::Ahead]);
+        let filter = Filter::try_from(&subscription).expect("invalid filter");
+        assert!(filter.matches(&message("repomon", "master", Category::Behind)));
+        assert!(filter.matches(&message("repomon", "master", Category::Ahead)));
+        assert!(!filter.matches(&message("repomon", "master", Category::UpToDate)));
+        assert!(!filter.matches(&message("repomon", "feature/x", Category::Behind)));
+    }
+
+    #[test]
+    fn invalid_glob() {
+        let mut subscription: Subscription = Default::default();
+        subscription.set_repos(vec!["[repomon".to_string()]);
+        assert!(Filter::try_from(&subscription).is_err());
+    }
+}
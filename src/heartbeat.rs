@@ -0,0 +1,48 @@
+// Copyright (c) 2017 repomons developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `repomons` heartbeats.
+//!
+//! Every heartbeat interval each client is sent a `Heartbeat` frame, and the
+//! clients that have pinged but since gone silent are dropped as dead.
+use error::Result;
+use futures::{Future, Stream};
+use log::Logs;
+use queue::SendError;
+use server::Server;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Instant;
+use tokio_core::reactor::{Handle, Interval};
+
+/// Start sending heartbeats, unless they are disabled.
+pub fn serve(handle: &Handle, server: Rc<RefCell<Server>>, logs: &Logs) -> Result<()> {
+    let interval = match server.borrow().heartbeat_config().interval() {
+        Some(interval) => interval,
+        None => {
+            try_trace!(logs.stdout(), "Heartbeats disabled");
+            return Ok(());
+        }
+    };
+
+    let tick_logs = logs.clone();
+    let heartbeats = Interval::new(interval, handle)?.for_each(move |_| {
+        let mut server = server.borrow_mut();
+        for addr in server.reap(Instant::now()) {
+            try_warn!(tick_logs.stdout(), "Client stopped pinging, disconnecting"; "addr" => format!("{}", addr));
+        }
+        for (addr, result) in server.heartbeat() {
+            if let Err(SendError::Full) = result {
+                try_warn!(tick_logs.stdout(), "Client queue full, disconnecting"; "addr" => format!("{}", addr));
+            }
+        }
+        Ok(())
+    });
+    handle.spawn(heartbeats.map_err(|_| ()));
+    Ok(())
+}
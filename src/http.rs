@@ -0,0 +1,308 @@
+// Copyright (c) 2017 repomons developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `repomons` HTTP/JSON status API.
+//!
+//! * `GET /repos` - The status of every monitored branch, by repository.
+//! * `GET /repos/<repo>` - The status of every monitored branch in a repository.
+//! * `GET /repos/<repo>/<branch>` - The status of a single branch.
+//! * `GET /events` - A Server-Sent Events stream of the monitor messages.
+//! * `GET /metrics` - The client queue metrics.
+//!
+//! When tokens are configured, every request must carry one in an
+//! `Authorization: Bearer <token>` header, and only sees the repositories the
+//! token may see.
+use error::Result;
+use filter::Filter;
+use futures::future::{self, FutureResult};
+use futures::{Future, Stream};
+use hyper::header::{Authorization, Bearer, ContentLength, ContentType};
+use hyper::server::{Http, Request, Response, Service};
+use hyper::{self, Chunk, Method, StatusCode};
+use log::Logs;
+use serde::Serialize;
+use serde_json;
+use server::Server;
+use sse;
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Handle;
+use url::form_urlencoded;
+
+/// The status API service.
+#[derive(Clone)]
+pub struct StatusService {
+    /// The handle to the event loop, used to spawn event streams.
+    handle: Handle,
+    /// The shared server state.
+    server: Rc<RefCell<Server>>,
+    /// The slog logs.
+    logs: Logs,
+}
+
+impl Service for StatusService {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = FutureResult<Response, hyper::Error>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let access = match self.server.borrow().access(token(&req).as_deref()) {
+            Some(access) => access,
+            None => return future::ok(unauthorized()),
+        };
+
+        if req.method() == &Method::Get && req.path() == "/events" {
+            return match sse::events(&req, access, &self.handle, &self.server, &self.logs) {
+                Ok(response) => future::ok(response),
+                Err(e) => future::ok(response(error(StatusCode::BadRequest, &e.to_string()))),
+            };
+        }
+
+        let (status, body) = if req.method() == &Method::Get {
+            route(&self.server.borrow(), req.path(), &access)
+        } else {
+            error(StatusCode::MethodNotAllowed, "method not allowed")
+        };
+
+        future::ok(response((status, body)))
+    }
+}
+
+/// Build a JSON response.
+fn response((status, body): (StatusCode, String)) -> Response {
+    Response::new()
+        .with_status(status)
+        .with_header(ContentType::json())
+        .with_header(ContentLength(body.len() as u64))
+        .with_body(body)
+}
+
+/// Build the response refusing a request without a valid token.
+fn unauthorized() -> Response {
+    let mut response = response(error(StatusCode::Unauthorized, "invalid or missing token"));
+    response.headers_mut().set_raw("WWW-Authenticate", "Bearer");
+    response
+}
+
+/// The bearer token sent with the request, or, for the event stream, as the
+/// `token` query parameter.
+fn token(req: &Request) -> Option<String> {
+    if let Some(authorization) = req.headers().get::<Authorization<Bearer>>() {
+        return Some(authorization.token.clone());
+    }
+    if req.path() != "/events" {
+        return None;
+    }
+    form_urlencoded::parse(req.query().unwrap_or("").as_bytes())
+        .find(|(key, _)| key == "token")
+        .map(|(_, token)| token.into_owned())
+}
+
+/// Route a `GET` request path to a status code and JSON body, showing only
+/// the repositories passing the access filter.  The metrics cover every
+/// client, so need access to every repository.
+fn route(server: &Server, path: &str, access: &Filter) -> (StatusCode, String) {
+    let segments: Vec<&str> = path.trim_matches('/').splitn(3, '/').collect();
+    let visible = |repo: &str| access.matches_repo(repo);
+
+    match segments.as_slice() {
+        ["metrics"] if access.matches_all_repos() => json(&server.metrics()),
+        ["metrics"] => error(StatusCode::Forbidden, "token not allowed to see metrics"),
+        ["repos"] => json(&server.statuses(|s| visible(s.repo()))),
+        ["repos", repo] => match server
+            .statuses(|s| s.repo() == *repo && visible(s.repo()))
+            .remove(*repo)
+        {
+            Some(branches) => json(&branches),
+            None => error(StatusCode::NotFound, "repository not found"),
+        },
+        ["repos", repo, branch] => {
+            match server
+                .statuses(|s| s.repo() == *repo && s.branch() == *branch && visible(s.repo()))
+                .remove(*repo)
+                .and_then(|mut branches| branches.remove(*branch))
+            {
+                Some(status) => json(&status),
+                None => error(StatusCode::NotFound, "branch not found"),
+            }
+        }
+        _ => error(StatusCode::NotFound, "not found"),
+    }
+}
+
+/// Serialize the given value into a JSON response body.
+fn json<T: Serialize>(value: &T) -> (StatusCode, String) {
+    match serde_json::to_string(value) {
+        Ok(body) => (StatusCode::Ok, body),
+        Err(e) => error(StatusCode::InternalServerError, &e.to_string()),
+    }
+}
+
+/// Generate a JSON error response body.
+fn error(status: StatusCode, message: &str) -> (StatusCode, String) {
+    (status, json!({ "error": message }).to_string())
+}
+
+/// Serve the status API on the given address.
+pub fn serve(
+    addr: &SocketAddr,
+    handle: &Handle,
+    server: Rc<RefCell<Server>>,
+    logs: &Logs,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr, handle)?;
+    try_trace!(logs.stdout(), "Listening for HTTP connections"; "addr" => format!("{}", addr));
+
+    let http = Http::<Chunk>::new();
+    let conn_handle = handle.clone();
+    let conn_logs = logs.clone();
+    let service = StatusService {
+        handle: handle.clone(),
+        server,
+        logs: logs.clone(),
+    };
+    let srv = listener
+        .incoming()
+        .for_each(move |(stream, addr)| {
+            try_trace!(conn_logs.stdout(), "HTTP connection opened"; "addr" => format!("{}", addr));
+            let error_logs = conn_logs.clone();
+            conn_handle.spawn(
+                http.serve_connection(stream, service.clone())
+                    .map(|_| ())
+                    .map_err(move |e| {
+                        try_error!(error_logs.stderr(), "HTTP connection error: {}", e; "addr" => format!("{}", addr))
+                    }),
+            );
+            Ok(())
+        })
+        .map_err(|_| ());
+    handle.spawn(srv);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{route, token};
+    use auth::Token;
+    use config::TokenConfig;
+    use event::{BranchStatus, Event, RemoteStatus};
+    use filter::Filter;
+    use hyper::header::{Authorization, Bearer};
+    use hyper::server::Request;
+    use hyper::{Method, StatusCode};
+    use repomon::Message;
+    use server::Server;
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
+    fn server() -> Server {
+        let mut server: Server = Default::default();
+        for &(repo, branch) in &[
+            ("repomon", "master"),
+            ("repomon", "feature/x"),
+            ("ar2", "master"),
+        ] {
+            let mut message: Message = Default::default();
+            message.set_repo(repo.to_string());
+            let mut status = BranchStatus::new(repo, branch);
+            status.set_remotes(vec![RemoteStatus::new("origin", 1, 2)]);
+            let _ = server.publish(&Event::new(message, status));
+        }
+        server
+    }
+
+    #[test]
+    fn all_repos() {
+        let (status, body) = route(&server(), "/repos", &Default::default());
+        assert_eq!(status, StatusCode::Ok);
+        assert!(body.starts_with(r#"{"ar2":{"master":{"repo":"ar2","branch":"master","#));
+        assert!(body.contains(r#""feature/x":{"repo":"repomon""#));
+    }
+
+    #[test]
+    fn single_repo() {
+        let (status, body) = route(&server(), "/repos/ar2", &Default::default());
+        assert_eq!(status, StatusCode::Ok);
+        assert!(body.contains(
+            r#""remotes":[{"remote":"origin","ahead":1,"behind":2,"category":"Behind","state":"Diverged","merge_base":null,"incoming":[],"outgoing":[],"diffstat":{"files":0,"insertions":0,"deletions":0,"paths":[]}}]"#
+        ));
+        assert_eq!(
+            route(&server(), "/repos/aoc", &Default::default()).0,
+            StatusCode::NotFound
+        );
+    }
+
+    #[test]
+    fn single_branch() {
+        let (status, body) = route(&server(), "/repos/repomon/feature/x", &Default::default());
+        assert_eq!(status, StatusCode::Ok);
+        assert!(body.starts_with(r#"{"repo":"repomon","branch":"feature/x","#));
+        assert_eq!(
+            route(&server(), "/repos/repomon/develop", &Default::default()).0,
+            StatusCode::NotFound
+        );
+        assert_eq!(
+            route(&server(), "/status", &Default::default()).0,
+            StatusCode::NotFound
+        );
+    }
+
+    #[test]
+    fn scoped_access() {
+        let mut config: TokenConfig = Default::default();
+        config.set_token("s3cret".to_string());
+        config.set_repos(vec!["repomon*".to_string()]);
+        let mut server = server();
+        server.set_auth(
+            vec![Token::try_from(&config).expect("")],
+            Duration::from_secs(10),
+        );
+        assert!(server.access(None).is_none());
+        assert!(server.access(Some("s3cre")).is_none());
+
+        let access: Filter = server.access(Some("s3cret")).expect("token not verified");
+        let (status, body) = route(&server, "/repos", &access);
+        assert_eq!(status, StatusCode::Ok);
+        assert!(body.starts_with(r#"{"repomon":"#));
+        assert!(!body.contains("ar2"));
+        assert_eq!(
+            route(&server, "/repos/ar2", &access).0,
+            StatusCode::NotFound
+        );
+        assert_eq!(
+            route(&server, "/repos/ar2/master", &access).0,
+            StatusCode::NotFound
+        );
+        assert_eq!(route(&server, "/metrics", &access).0, StatusCode::Forbidden);
+        assert_eq!(
+            route(&server, "/metrics", &Default::default()).0,
+            StatusCode::Ok
+        );
+    }
+
+    #[test]
+    fn tokens() {
+        let mut req = Request::new(Method::Get, "/repos".parse().expect(""));
+        assert_eq!(token(&req), None);
+        req.headers_mut().set(Authorization(Bearer {
+            token: "s3cret".to_string(),
+        }));
+        assert_eq!(token(&req), Some("s3cret".to_string()));
+
+        let events = Request::new(
+            Method::Get,
+            "/events?repo=ar2&token=s3cret".parse().expect(""),
+        );
+        assert_eq!(token(&events), Some("s3cret".to_string()));
+        let repos = Request::new(Method::Get, "/repos?token=s3cret".parse().expect(""));
+        assert_eq!(token(&repos), None);
+    }
+}
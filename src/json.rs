@@ -0,0 +1,304 @@
+// Copyright (c) 2017 repomons developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `repomons` JSON views of the server frames.
+//!
+//! JSON object keys must be strings, so the `Branch` and `Remote` keys of a
+//! `Message` are replaced with their names.  The same views are used for the
+//! CBOR and MessagePack encodings, so every non-bincode client sees the same
+//! structure.  A `StatusEvent` is sent as an `Event` carrying the status of
+//! its branch.
+use error::Result;
+use event::{BranchStatus, Event, RefChange};
+use protocol::{Reply, Response, ServerFrame, Versioned};
+use repomon::{Category, Message};
+use rmp_serde;
+use serde_cbor;
+use serde_json;
+use std::collections::BTreeMap;
+
+/// JSON view of a monitor message.
+#[derive(Serialize)]
+struct JsonMessage<'a> {
+    /// The unique message identifier, as a string in every encoding.
+    uuid: String,
+    /// The message category.
+    category: &'a Category,
+    /// The repo name.
+    repo: &'a str,
+    /// The messages per branch/remote name.
+    messages: BTreeMap<&'a str, BTreeMap<&'a str, &'a str>>,
+    /// The status of the branch the message is for, if sent with it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<&'a BranchStatus>,
+    /// The change to the branches on a remote the message reports, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    change: Option<&'a RefChange>,
+}
+
+impl<'a> JsonMessage<'a> {
+    /// The view of a message sent with the status of its branch, and the
+    /// branch change it reports, if any.
+    fn with_status(
+        message: &'a Message,
+        status: &'a BranchStatus,
+        change: Option<&'a RefChange>,
+    ) -> Self {
+        Self {
+            status: Some(status),
+            change,
+            ..Self::from(message)
+        }
+    }
+}
+
+impl<'a> From<&'a Message> for JsonMessage<'a> {
+    fn from(message: &'a Message) -> Self {
+        let messages = message
+            .messages()
+            .iter()
+            .map(|(branch, remotes)| {
+                let remotes = remotes
+                    .iter()
+                    .map(|(remote, text)| (remote.name().as_str(), text.as_str()))
+                    .collect();
+                (branch.name().as_str(), remotes)
+            })
+            .collect();
+
+        Self {
+            uuid: message.uuid().to_string(),
+            category: message.category(),
+            repo: message.repo(),
+            messages,
+            status: None,
+            change: None,
+        }
+    }
+}
+
+/// JSON view of a reply.
+#[derive(Serialize)]
+enum JsonReply<'a> {
+    /// The command succeeded with nothing to report.
+    Ok,
+    /// The id of a new subscription.
+    Subscribed(u64),
+    /// The monitored repositories, mapped to their monitored branches.
+    Repos(&'a BTreeMap<String, Vec<String>>),
+    /// The latest message for each monitored repository/branch.
+    Status(Vec<JsonMessage<'a>>),
+    /// The answer to a ping.
+    Pong,
+}
+
+impl<'a> From<&'a Reply> for JsonReply<'a> {
+    fn from(reply: &'a Reply) -> Self {
+        match *reply {
+            Reply::Ok => JsonReply::Ok,
+            Reply::Subscribed(id) => JsonReply::Subscribed(id),
+            Reply::Repos(ref repos) => JsonReply::Repos(repos),
+            Reply::Status(ref messages) => {
+                JsonReply::Status(messages.iter().map(JsonMessage::from).collect())
+            }
+            Reply::Pong => JsonReply::Pong,
+        }
+    }
+}
+
+/// JSON view of a response.
+#[derive(Serialize)]
+struct JsonResponse<'a> {
+    /// The id of the request this is a response to.
+    id: u64,
+    /// The reply, or an error message.
+    result: ::std::result::Result<JsonReply<'a>, &'a str>,
+}
+
+impl<'a> From<&'a Response> for JsonResponse<'a> {
+    fn from(response: &'a Response) -> Self {
+        Self {
+            id: *response.id(),
+            result: match *response.result() {
+                Ok(ref reply) => Ok(JsonReply::from(reply)),
+                Err(ref e) => Err(e.as_str()),
+            },
+        }
+    }
+}
+
+/// JSON view of a server frame.
+#[derive(Serialize)]
+enum JsonFrame<'a> {
+    /// The latest message for each monitored repository/branch, sent on connect.
+    Snapshot(Vec<JsonMessage<'a>>),
+    /// A monitor message.
+    Event(JsonMessage<'a>),
+    /// The response to a client request.
+    Response(JsonResponse<'a>),
+    /// Sent every heartbeat interval.
+    Heartbeat,
+    /// The last frame sent before the server shuts down.
+    Shutdown,
+}
+
+impl<'a> From<&'a ServerFrame> for JsonFrame<'a> {
+    fn from(frame: &'a ServerFrame) -> Self {
+        match *frame {
+            ServerFrame::Snapshot(ref messages) => {
+                JsonFrame::Snapshot(messages.iter().map(JsonMessage::from).collect())
+            }
+            ServerFrame::Event(ref message) => JsonFrame::Event(JsonMessage::from(message)),
+            ServerFrame::Response(ref response) => {
+                JsonFrame::Response(JsonResponse::from(response))
+            }
+            ServerFrame::Heartbeat => JsonFrame::Heartbeat,
+            ServerFrame::Shutdown => JsonFrame::Shutdown,
+            ServerFrame::StatusEvent(ref event) => JsonFrame::Event(JsonMessage::with_status(
+                event.message(),
+                event.status(),
+                event.change().as_ref(),
+            )),
+        }
+    }
+}
+
+/// Serialize a server frame to a JSON string.
+pub fn to_string(frame: &ServerFrame) -> Result<String> {
+    Ok(serde_json::to_string(&JsonFrame::from(frame))?)
+}
+
+/// Serialize a server frame to JSON for the given protocol version.
+pub fn to_vec(frame: &ServerFrame, version: u8) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&Versioned::new(
+        version,
+        JsonFrame::from(frame),
+    ))?)
+}
+
+/// Serialize a server frame to CBOR for the given protocol version.
+pub fn to_cbor(frame: &ServerFrame, version: u8) -> Result<Vec<u8>> {
+    Ok(serde_cbor::to_vec(&Versioned::new(
+        version,
+        JsonFrame::from(frame),
+    ))?)
+}
+
+/// Serialize a server frame to MessagePack, with structs as maps, for the
+/// given protocol version.
+pub fn to_msgpack(frame: &ServerFrame, version: u8) -> Result<Vec<u8>> {
+    Ok(rmp_serde::to_vec_named(&Versioned::new(
+        version,
+        JsonFrame::from(frame),
+    ))?)
+}
+
+/// Serialize the message of an event, with the status, or branch change,
+/// behind it, to a JSON string.
+pub fn event_to_string(event: &Event) -> Result<String> {
+    Ok(serde_json::to_string(&JsonMessage::with_status(
+        event.message(),
+        event.status(),
+        event.change().as_ref(),
+    ))?)
+}
+
+#[cfg(test)]
+mod test {
+    use event::{BranchStatus, RefChange, RemoteStatus};
+    use protocol::{Reply, Response, ServerFrame, StatusEvent};
+    use repomon::{Branch, Category, Message, Remote};
+    use serde_json::{self, Value};
+    use std::collections::BTreeMap;
+    use uuid::Uuid;
+
+    #[test]
+    fn event() {
+        let mut branch: Branch = Default::default();
+        branch.set_name("master".to_string());
+        let mut remote: Remote = Default::default();
+        remote.set_name("origin/master".to_string());
+        let mut remotes = BTreeMap::new();
+        remotes.insert(remote, "Your branch is up to date".to_string());
+        let mut messages = BTreeMap::new();
+        messages.insert(branch, remotes);
+
+        let mut message: Message = Default::default();
+        message.set_uuid(Uuid::nil());
+        message.set_category(Category::UpToDate);
+        message.set_repo("repomon".to_string());
+        message.set_messages(messages);
+
+        assert_eq!(
+            super::to_string(&ServerFrame::Event(message)).expect(""),
+            r#"{"Event":{"uuid":"00000000-0000-0000-0000-000000000000","category":"UpToDate","repo":"repomon","messages":{"master":{"origin/master":"Your branch is up to date"}}}}"#
+        );
+    }
+
+    #[test]
+    fn response() {
+        let ok = ServerFrame::Response(Response::new(1, Ok(Reply::Subscribed(2))));
+        assert_eq!(
+            super::to_string(&ok).expect(""),
+            r#"{"Response":{"id":1,"result":{"Ok":{"Subscribed":2}}}}"#
+        );
+        let err = ServerFrame::Response(Response::new(3, Err("unknown".to_string())));
+        assert_eq!(
+            super::to_string(&err).expect(""),
+            r#"{"Response":{"id":3,"result":{"Err":"unknown"}}}"#
+        );
+    }
+
+    #[test]
+    fn status_event() {
+        let mut message: Message = Default::default();
+        message.set_uuid(Uuid::nil());
+        message.set_category(Category::Behind);
+        message.set_repo("repomon".to_string());
+        let mut remote = RemoteStatus::new("origin", 3, 5);
+        remote.set_merge_base(Some("37074407c2b984ef297d58981504458fbe91b930".to_string()));
+        let mut status = BranchStatus::new("repomon", "master");
+        status.set_remotes(vec![remote]);
+        let frame = ServerFrame::StatusEvent(Box::new(StatusEvent::new(message, status, None)));
+
+        let json: Value = serde_json::from_str(&super::to_string(&frame).expect("")).expect("");
+        let event = &json["Event"];
+        assert_eq!(event["category"], "Behind");
+        assert_eq!(event["status"]["branch"], "master");
+        assert_eq!(
+            event["status"]["remotes"][0],
+            json!({
+                "remote": "origin",
+                "ahead": 3,
+                "behind": 5,
+                "category": "Behind",
+                "state": "Diverged",
+                "merge_base": "37074407c2b984ef297d58981504458fbe91b930",
+                "incoming": [],
+                "outgoing": [],
+                "diffstat": {"files": 0, "insertions": 0, "deletions": 0, "paths": []}
+            })
+        );
+        assert!(event.get("change").is_none());
+    }
+
+    #[test]
+    fn ref_change_event() {
+        let change =
+            RefChange::Renamed("topic".to_string(), "feature".to_string(), "c".to_string());
+        let event = change.event("repomon", "origin");
+
+        let json: Value =
+            serde_json::from_str(&super::event_to_string(&event).expect("")).expect("");
+        assert_eq!(json["category"], "Info");
+        assert_eq!(
+            json["change"],
+            json!({"Renamed": ["topic", "feature", "c"]})
+        );
+    }
+}
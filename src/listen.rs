@@ -0,0 +1,324 @@
+// Copyright (c) 2017 repomons developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `repomons` monitor listeners.
+//!
+//! Monitor clients connect over TCP, optionally with TLS, or over a Unix
+//! socket, whose access is controlled by its filesystem permissions.
+use auth;
+use codec;
+use encoding::{self, Hello};
+use error::{Error, Result};
+use futures::sync::oneshot;
+use futures::{Future, Sink, Stream};
+use log::Logs;
+use openssl::ssl::SslAcceptor;
+use protocol::ServerFrame;
+use server::{Client, Peer, Server};
+use std::cell::RefCell;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
+use tls;
+use tokio_codec::{FramedRead, FramedWrite};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Handle;
+use tokio_io::io::write_all;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// The prefix of a Unix socket listen address.
+const UNIX_PREFIX: &str = "unix:";
+
+/// A monitor listen address.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Address {
+    /// A TCP socket address.
+    Tcp(SocketAddr),
+    /// A Unix socket path, given as `unix:<path>`.
+    Unix(PathBuf),
+}
+
+impl FromStr for Address {
+    type Err = Error;
+
+    fn from_str(address: &str) -> Result<Self> {
+        match address.strip_prefix(UNIX_PREFIX) {
+            Some("") => Err(format!("invalid address: {}", address).into()),
+            Some(path) => Ok(Address::Unix(PathBuf::from(path))),
+            None => Ok(Address::Tcp(address.parse()?)),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Address::Tcp(ref addr) => write!(f, "{}", addr),
+            Address::Unix(ref path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// Listen for monitor clients on the given address, returning the future
+/// accepting them.  TLS, when enabled, only applies to TCP listeners.
+pub fn serve(
+    address: &Address,
+    handle: &Handle,
+    server: &Rc<RefCell<Server>>,
+    acceptor: Option<&SslAcceptor>,
+    logs: &Logs,
+) -> Result<Box<dyn Future<Item = (), Error = ()>>> {
+    match *address {
+        Address::Tcp(ref addr) => serve_tcp(addr, handle, server, acceptor, logs),
+        Address::Unix(ref path) => serve_unix(path, handle, server, logs),
+    }
+}
+
+/// Listen for monitor clients on the given TCP address.
+fn serve_tcp(
+    addr: &SocketAddr,
+    handle: &Handle,
+    server: &Rc<RefCell<Server>>,
+    acceptor: Option<&SslAcceptor>,
+    logs: &Logs,
+) -> Result<Box<dyn Future<Item = (), Error = ()>>> {
+    let listener = TcpListener::bind(addr, handle)?;
+    try_trace!(logs.stdout(), "Listening for connections"; "addr" => format!("{}", addr));
+
+    let acceptor = acceptor.cloned();
+    let keepalive = server.borrow().heartbeat_config().interval();
+    let srv_handle = handle.clone();
+    let srv_server = Rc::clone(server);
+    let srv_logs = logs.clone();
+    let srv = listener.incoming().for_each(move |(stream, addr)| {
+        let addr = Peer::Tcp(addr);
+        try_trace!(srv_logs.stdout(), "Connection opened"; "addr" => format!("{}", addr));
+        if let Err(e) = stream.set_keepalive(keepalive) {
+            try_warn!(srv_logs.stdout(), "Unable to set TCP keepalive: {}", e; "addr" => format!("{}", addr));
+        }
+
+        if let Some(ref acceptor) = acceptor {
+            let conn_handle = srv_handle.clone();
+            let conn_server = Rc::clone(&srv_server);
+            let conn_logs = srv_logs.clone();
+            let error_logs = srv_logs.clone();
+            srv_handle.spawn(
+                tls::accept(acceptor, stream, tls::HANDSHAKE_TIMEOUT, &srv_handle)
+                    .map(move |stream| connected(stream, addr, &conn_handle, &conn_server, &conn_logs))
+                    .map_err(move |e| {
+                        try_error!(error_logs.stderr(), "{}", e; "addr" => format!("{}", addr))
+                    }),
+            );
+        } else {
+            connected(stream, addr, &srv_handle, &srv_server, &srv_logs);
+        }
+
+        Ok(())
+    });
+
+    Ok(Box::new(srv.map_err(|_| ())))
+}
+
+/// Remove a stale socket left at the given path by a previous run.  A socket
+/// still accepting connections belongs to a running server, and is left alone.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixStream;
+
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            match UnixStream::connect(path) {
+                Err(ref e) if e.kind() == ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+                _ => return Err(format!("address in use: {}", path.display()).into()),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Listen for monitor clients on the given Unix socket path.  A stale socket
+/// left at the path by a previous run is replaced.
+#[cfg(unix)]
+fn serve_unix(
+    path: &Path,
+    handle: &Handle,
+    server: &Rc<RefCell<Server>>,
+    logs: &Logs,
+) -> Result<Box<dyn Future<Item = (), Error = ()>>> {
+    use tokio_uds::UnixListener;
+
+    remove_stale_socket(path)?;
+    let listener = UnixListener::bind(path, handle)?;
+    try_trace!(logs.stdout(), "Listening for connections"; "path" => format!("{}", path.display()));
+
+    let srv_handle = handle.clone();
+    let srv_server = Rc::clone(server);
+    let srv_logs = logs.clone();
+    let srv = listener.incoming().for_each(move |(stream, _)| {
+        let addr = srv_server.borrow_mut().unix_peer();
+        try_trace!(srv_logs.stdout(), "Connection opened"; "addr" => format!("{}", addr));
+        connected(stream, addr, &srv_handle, &srv_server, &srv_logs);
+        Ok(())
+    });
+
+    Ok(Box::new(srv.map_err(|_| ())))
+}
+
+/// Unix sockets are only available on Unix platforms.
+#[cfg(not(unix))]
+fn serve_unix(
+    path: &Path,
+    _handle: &Handle,
+    _server: &Rc<RefCell<Server>>,
+    _logs: &Logs,
+) -> Result<Box<dyn Future<Item = (), Error = ()>>> {
+    Err(format!(
+        "Unix sockets are not supported on this platform: {}",
+        path.display()
+    )
+    .into())
+}
+
+/// Serve a newly connected monitor client on the given stream.
+fn connected<S>(stream: S, addr: Peer, handle: &Handle, server: &Rc<RefCell<Server>>, logs: &Logs)
+where
+    S: AsyncRead + AsyncWrite + 'static,
+{
+    let (reader, writer) = stream.split();
+
+    // Create a queue for our stream, which other sockets will use to send us
+    // messages. Then register our address with the stream to send data to us.
+    // Both halves stop once the client is disconnected, e.g. for letting its
+    // queue fill up.
+    let (tx, rx) = server.borrow().queue();
+    let writer_closed = rx.closed();
+    let reader_closed = rx.closed();
+    if !server.borrow_mut().add_client(addr, Client::new(tx)) {
+        try_error!(logs.stderr(), "Error sending snapshot"; "addr" => format!("{}", addr));
+    }
+
+    // Write the preamble and wait for the client hello, then
+    // frame every message we receive on the Receiver onto the `WriteHalf`,
+    // starting with the snapshot queued when the client was added.
+    let (hello_tx, hello_rx) = oneshot::channel::<Hello>();
+    let writer_logs = logs.clone();
+    let socket_writer = write_all(writer, codec::preamble())
+        .map_err(|_| ())
+        .join(hello_rx.map_err(|_| ()))
+        .and_then(move |((writer, _), hello)| {
+            let framed = FramedWrite::new(writer, hello.encoding().codec()).sink_map_err(|_| ());
+            rx.filter_map(move |frame| hello.downgrade(frame)).and_then(move |frame: ServerFrame| {
+                try_trace!(writer_logs.stdout(), "Sending frame"; "addr" => format!("{}", addr), "encoding" => format!("{}", hello.encoding()), "version" => hello.version());
+                hello.encode(&frame).map_err(|_| ())
+            }).forward(framed)
+        });
+
+    // Make the socket write future into a future that can be spawned.
+    let socket_writer = socket_writer.map(|_| ()).select(writer_closed);
+
+    let writer_server = Rc::clone(server);
+    let spawn_logs = logs.clone();
+    handle.spawn(socket_writer.then(move |_| {
+        try_trace!(spawn_logs.stdout(), "Closing connection"; "addr" => format!("{}", addr));
+        writer_server.borrow_mut().remove_client(&addr);
+        Ok(())
+    }));
+
+    // Read the hello line, then decode each request frame from the
+    // `ReadHalf`, and queue the response on our Receiver, until the client
+    // closes the connection or fails to authenticate in time.
+    let reader_server = Rc::clone(server);
+    let reader_logs = logs.clone();
+    let error_logs = logs.clone();
+    let socket_reader = encoding::negotiate(reader)
+        .and_then(move |(reader, hello)| {
+            try_trace!(reader_logs.stdout(), "Hello received"; "addr" => format!("{}", addr), "encoding" => format!("{}", hello.encoding()), "version" => hello.version());
+            let _ = hello_tx.send(hello);
+            FramedRead::new(reader, hello.encoding().codec()).for_each(move |frame| {
+                match hello.decode(&frame) {
+                    Ok(request) => {
+                        try_trace!(reader_logs.stdout(), "Received request"; "addr" => format!("{}", addr), "id" => request.id());
+                        if !reader_server.borrow_mut().handle(&addr, &request) {
+                            try_error!(reader_logs.stderr(), "Error sending response"; "addr" => format!("{}", addr));
+                        }
+                    }
+                    Err(e) => {
+                        try_error!(reader_logs.stderr(), "Invalid request: {}", e; "addr" => format!("{}", addr))
+                    }
+                }
+                Ok(())
+            })
+        })
+        .map_err(move |e| {
+            try_error!(error_logs.stderr(), "Connection error: {}", e; "addr" => format!("{}", addr))
+        });
+    let deadline_logs = logs.clone();
+    let deadline = auth::deadline(handle, server, addr).map(move |_| {
+        try_warn!(deadline_logs.stdout(), "Authentication timed out"; "addr" => format!("{}", addr))
+    });
+    let socket_reader = socket_reader
+        .select(deadline)
+        .map(|_| ())
+        .map_err(|_| ())
+        .select(reader_closed);
+
+    let reader_server = Rc::clone(server);
+    let spawn_logs = logs.clone();
+    handle.spawn(socket_reader.then(move |_| {
+        try_trace!(spawn_logs.stdout(), "Connection closed by client"; "addr" => format!("{}", addr));
+        reader_server.borrow_mut().remove_client(&addr);
+        Ok(())
+    }));
+}
+
+#[cfg(test)]
+mod test {
+    use super::Address;
+    use std::path::PathBuf;
+
+    #[test]
+    fn address() {
+        assert_eq!(
+            "127.0.0.1:8080".parse::<Address>().expect(""),
+            Address::Tcp("127.0.0.1:8080".parse().expect(""))
+        );
+        assert_eq!(
+            "unix:/run/repomons.sock".parse::<Address>().expect(""),
+            Address::Unix(PathBuf::from("/run/repomons.sock"))
+        );
+        assert!("unix:".parse::<Address>().is_err());
+        assert!("localhost".parse::<Address>().is_err());
+        assert_eq!(
+            Address::Unix(PathBuf::from("/run/repomons.sock")).to_string(),
+            "unix:/run/repomons.sock"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_socket() {
+        use std::env;
+        use std::os::unix::net::UnixListener;
+        use std::process;
+
+        let path = env::temp_dir().join(format!("repomons-{}.sock", process::id()));
+        let listener = UnixListener::bind(&path).expect("unable to bind socket");
+        assert!(super::remove_stale_socket(&path).is_err());
+        assert!(path.exists());
+
+        drop(listener);
+        assert!(super::remove_stale_socket(&path).is_ok());
+        assert!(!path.exists());
+        assert!(super::remove_stale_socket(&path).is_ok());
+    }
+}
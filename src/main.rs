@@ -14,30 +14,70 @@ extern crate error_chain;
 #[macro_use]
 extern crate getset;
 #[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+#[macro_use]
 extern crate slog;
 #[macro_use]
 extern crate slog_try;
 
 extern crate bincode;
+extern crate bytes;
+extern crate chrono;
 extern crate clap;
 extern crate colored;
 extern crate futures;
+extern crate futures_cpupool;
 extern crate git2;
+extern crate glob;
+extern crate hyper;
+extern crate openssl;
 extern crate rand;
 extern crate repomon;
+extern crate rmp_serde;
+extern crate serde;
+extern crate serde_cbor;
 extern crate slog_async;
 extern crate slog_term;
 extern crate term;
+extern crate tokio_codec;
 extern crate tokio_core;
 extern crate tokio_io;
+extern crate tokio_signal;
+#[cfg(unix)]
+extern crate tokio_uds;
+extern crate toml;
+extern crate url;
 extern crate uuid;
+extern crate websocket;
 
+mod auth;
 mod branch;
 mod callbacks;
+mod codec;
+mod config;
+mod encoding;
 mod error;
+mod event;
+mod filter;
+mod heartbeat;
+mod http;
+mod json;
+mod listen;
 mod log;
+mod monitors;
+mod protocol;
+mod queue;
+mod reload;
 mod repo;
 mod run;
+mod scheduler;
+mod server;
+mod shutdown;
+mod sse;
+mod tls;
+mod ws;
 
 use std::io::{self, Write};
 use std::process;
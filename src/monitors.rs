@@ -0,0 +1,329 @@
+// Copyright (c) 2017 repomons developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `repomons` monitors.
+//!
+//! There is one monitor, scheduled by the `scheduler`, per repository/branch
+//! pair in the configuration.  Applying a new configuration starts monitors for the new
+//! pairs, stops the removed ones and restarts the ones whose settings changed.
+//!
+//! A branch name containing a glob pattern, e.g. `release/*` or `*`, monitors
+//! every branch on its remotes matching the pattern.  The pattern is expanded
+//! on every check, so matching branches are monitored as they appear on the
+//! remotes, and retired as they disappear.
+use branch::MonitorConfig;
+use config::Config;
+use error::Result;
+use glob::Pattern;
+use log::Logs;
+use repo;
+use repomon::{Branch, Remote, Repomon};
+use scheduler::Scheduler;
+use server::Server;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// A repository/branch pair.
+pub type Key = (String, String);
+
+/// Is the given branch name a glob pattern?
+pub fn is_pattern(branch: &str) -> bool {
+    branch.contains(['*', '?', '['])
+}
+
+/// The settings a monitor is started with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    /// The absolute path of the repository.
+    path: PathBuf,
+    /// The branch to monitor.
+    branch: Branch,
+    /// The remotes of the repository.
+    remotes: Vec<Remote>,
+    /// The remote the other remotes of the repository mirror.
+    mirror_source: Option<String>,
+    /// The branches of the repository monitored by name, for a pattern to
+    /// leave out.
+    named: Vec<String>,
+}
+
+/// The monitor settings for every repository/branch pair in a configuration,
+/// with the repository settings of the `repomons` configuration.  The mirror
+/// source of a repository must be one of the remotes of each of its branches,
+/// as the mirrors are compared against it.
+pub fn settings(repomon: &Repomon, config: &Config) -> Result<BTreeMap<Key, Settings>> {
+    let mut settings = BTreeMap::new();
+    for (repo_name, repo) in repomon.repos() {
+        let repo_config = config.repos().get(repo_name);
+        let path = repo::path(
+            Path::new(repomon.basedir()),
+            repo_name,
+            repo_config
+                .and_then(|repo_config| repo_config.path().as_ref())
+                .map(|path| path.as_path()),
+        )?;
+        let mirror_source = repo_config.and_then(|repo_config| repo_config.mirror_source().clone());
+        let named: Vec<String> = repo
+            .branch()
+            .iter()
+            .map(|branch| branch.name().clone())
+            .filter(|name| !is_pattern(name))
+            .collect();
+        for branch in repo.branch() {
+            if is_pattern(branch.name()) {
+                let _ = Pattern::new(branch.name())?;
+            }
+            if let Some(ref source) = mirror_source {
+                if !branch.remotes().contains(source) {
+                    return Err(format!(
+                        "mirror source '{}' is not a remote of branch '{}' in '{}'",
+                        source,
+                        branch.name(),
+                        repo_name
+                    )
+                    .into());
+                }
+            }
+            settings.insert(
+                (repo_name.clone(), branch.name().clone()),
+                Settings {
+                    path: path.clone(),
+                    branch: branch.clone(),
+                    remotes: repo.remotes().clone(),
+                    mirror_source: mirror_source.clone(),
+                    named: if is_pattern(branch.name()) {
+                        named.clone()
+                    } else {
+                        Vec::new()
+                    },
+                },
+            );
+        }
+    }
+    Ok(settings)
+}
+
+/// The changes between two sets of monitor settings.
+#[derive(Debug, Default, Getters, PartialEq)]
+pub struct Diff {
+    /// The new repository/branch pairs.
+    #[get = "pub"]
+    added: Vec<Key>,
+    /// The removed repository/branch pairs.
+    #[get = "pub"]
+    removed: Vec<Key>,
+    /// The repository/branch pairs whose settings changed.
+    #[get = "pub"]
+    changed: Vec<Key>,
+}
+
+/// Diff two sets of monitor settings.
+pub fn diff(old: &BTreeMap<Key, Settings>, new: &BTreeMap<Key, Settings>) -> Diff {
+    let mut diff: Diff = Default::default();
+    for (key, settings) in new {
+        match old.get(key) {
+            None => diff.added.push(key.clone()),
+            Some(old_settings) if old_settings != settings => diff.changed.push(key.clone()),
+            Some(_) => {}
+        }
+    }
+    diff.removed = old
+        .keys()
+        .filter(|key| !new.contains_key(*key))
+        .cloned()
+        .collect();
+    diff
+}
+
+/// The running monitors.
+pub struct Monitors {
+    /// The configuration each monitor is started from.
+    config: MonitorConfig,
+    /// The shared server state, where each monitor registers its control.
+    server: Rc<RefCell<Server>>,
+    /// The scheduler running the branch checks.
+    scheduler: Scheduler,
+    /// The slog logs.
+    logs: Logs,
+    /// The settings of the running monitors.
+    settings: BTreeMap<Key, Settings>,
+}
+
+impl Monitors {
+    /// Create a new, empty, set of monitors.
+    pub fn new(
+        config: MonitorConfig,
+        server: Rc<RefCell<Server>>,
+        scheduler: Scheduler,
+        logs: Logs,
+    ) -> Self {
+        Self {
+            config,
+            server,
+            scheduler,
+            logs,
+            settings: BTreeMap::new(),
+        }
+    }
+
+    /// Start, stop and restart monitors to match the given configuration.
+    pub fn apply(&mut self, repomon: &Repomon, config: &Config) -> Result<Diff> {
+        let settings = settings(repomon, config)?;
+        let diff = diff(&self.settings, &settings);
+
+        for key in diff.removed.iter().chain(&diff.changed) {
+            try_trace!(self.logs.stdout(), "Stopping monitor"; "repository" => &key.0, "branch" => &key.1);
+            self.server.borrow_mut().remove_monitor(&key.0, &key.1);
+        }
+        for key in diff.added.iter().chain(&diff.changed) {
+            self.start(key, &settings[key]);
+        }
+
+        self.settings = settings;
+        Ok(diff)
+    }
+
+    /// Schedule the monitor for a repository/branch pair.
+    fn start(&mut self, key: &Key, settings: &Settings) {
+        let (repo_name, branch_name) = key.clone();
+        self.config.set_path(settings.path.clone());
+        self.config.set_repo_name(repo_name.clone());
+        self.config.set_branch(settings.branch.clone());
+        self.config.set_remotes(settings.remotes.clone());
+        self.config
+            .set_mirror_source(settings.mirror_source.clone());
+        self.config.set_named(settings.named.clone());
+
+        // The control lets clients ask for a check now.
+        let control = self.scheduler.add(key.clone(), self.config.clone());
+        self.server
+            .borrow_mut()
+            .add_monitor(&repo_name, &branch_name, control);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{diff, is_pattern, settings};
+    use config::Config;
+    use repomon;
+    use std::io::Cursor;
+    use std::path::Path;
+
+    fn config(toml: &str) -> repomon::Repomon {
+        repomon::read_toml(&mut Cursor::new(toml)).expect("invalid config")
+    }
+
+    const BASE: &str = r#"basedir = "/tmp"
+
+        [[repos.ar2.remotes]]
+        name = "origin"
+        url = "/tmp/ar2.git"
+
+        [[repos.ar2.branch]]
+        name = "master"
+        interval = "1m"
+        remotes = ["origin"]
+
+        [[repos.ar2.branch]]
+        name = "develop"
+        interval = "1m"
+        remotes = ["origin"]
+        "#;
+
+    #[test]
+    fn settings_per_branch() {
+        let settings = settings(&config(BASE), &Default::default()).expect("");
+        assert_eq!(
+            settings.keys().collect::<Vec<_>>(),
+            vec![
+                &("ar2".to_string(), "develop".to_string()),
+                &("ar2".to_string(), "master".to_string())
+            ]
+        );
+        assert!(settings
+            .values()
+            .all(|settings| settings.path == Path::new("/tmp/ar2")));
+    }
+
+    #[test]
+    fn repo_settings() {
+        let old = settings(&config(BASE), &Default::default()).expect("");
+        let toml = BASE.replace(
+            "basedir = \"/tmp\"\n",
+            "basedir = \"/tmp\"\n\n[repos.ar2]\npath = \"mirrors/ar2\"\nmirror_source = \"origin\"\n",
+        );
+        let new = settings(
+            &config(&toml),
+            &Config::from_toml(&toml).expect("invalid config"),
+        )
+        .expect("");
+        assert!(new
+            .values()
+            .all(|settings| settings.path == Path::new("/tmp/mirrors/ar2")
+                && settings.mirror_source == Some("origin".to_string())));
+        assert_eq!(diff(&old, &new).changed().len(), 2);
+
+        // The mirror source must be compared against by every branch.
+        let toml = toml.replace("mirror_source = \"origin\"", "mirror_source = \"gh\"");
+        assert!(settings(
+            &config(&toml),
+            &Config::from_toml(&toml).expect("invalid config"),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn diff_settings() {
+        let old = settings(&config(BASE), &Default::default()).expect("");
+        assert_eq!(diff(&old, &old), Default::default());
+
+        let new = settings(
+            &config(
+                &BASE
+                    .replace(
+                        "name = \"develop\"\n        interval = \"1m\"",
+                        "name = \"develop\"\n        interval = \"5m\"",
+                    )
+                    .replace("name = \"master\"", "name = \"main\""),
+            ),
+            &Default::default(),
+        )
+        .expect("");
+        let diff = diff(&old, &new);
+        assert_eq!(diff.added(), &[("ar2".to_string(), "main".to_string())]);
+        assert_eq!(diff.removed(), &[("ar2".to_string(), "master".to_string())]);
+        assert_eq!(
+            diff.changed(),
+            &[("ar2".to_string(), "develop".to_string())]
+        );
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(is_pattern("release/*"));
+        assert!(is_pattern("*"));
+        assert!(is_pattern("v1.?"));
+        assert!(is_pattern("[ab]"));
+        assert!(!is_pattern("feature/x"));
+
+        let toml = BASE.replace("name = \"develop\"", "name = \"release/*\"");
+        let settings = settings(&config(&toml), &Default::default()).expect("");
+        // A pattern leaves out the branches of its repository monitored by name.
+        let pattern = &settings[&("ar2".to_string(), "release/*".to_string())];
+        assert_eq!(pattern.named, vec!["master".to_string()]);
+        assert!(settings[&("ar2".to_string(), "master".to_string())]
+            .named
+            .is_empty());
+
+        let toml = BASE.replace("name = \"develop\"", "name = \"release/[\"");
+        assert!(super::settings(&config(&toml), &Default::default()).is_err());
+    }
+}
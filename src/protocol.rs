@@ -0,0 +1,211 @@
+// Copyright (c) 2017 repomons developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `repomons` client/server protocol.
+//!
+//! Clients send `Request`s in the encoding picked for the connection.  The
+//! server answers every request with a `Response` carrying the same id, and
+//! interleaves those with monitor events, all wrapped in a `ServerFrame`.
+//!
+//! From `ENVELOPE_VERSION`, frames and requests in both directions are
+//! wrapped in an `Envelope` carrying the protocol version they were
+//! serialized with, so a client can reject a schema it does not know rather
+//! than misread it.
+//!
+//! From `STATUS_VERSION`, monitor messages are sent as `StatusEvent` frames,
+//! carrying the structured status, or branch change, behind the message,
+//! rather than as bare `Event` frames.
+use event::{BranchStatus, RefChange};
+use repomon::{Category, Message};
+use std::collections::BTreeMap;
+
+/// The first protocol version that wraps frames in an `Envelope`.
+pub const ENVELOPE_VERSION: u8 = 7;
+/// The first protocol version that is sent `Heartbeat` frames.
+pub const HEARTBEAT_VERSION: u8 = 8;
+/// The first protocol version that is sent the `Shutdown` frame.
+pub const SHUTDOWN_VERSION: u8 = 9;
+/// The first protocol version that is sent `StatusEvent` frames in place of
+/// `Event` frames.
+pub const STATUS_VERSION: u8 = 10;
+
+/// A request sent from a client to the server.
+#[derive(Clone, Debug, Deserialize, Getters, PartialEq, Serialize)]
+pub struct Request {
+    /// The client chosen request identifier, echoed in the response.
+    #[get = "pub"]
+    id: u64,
+    /// The command to run.
+    #[get = "pub"]
+    command: Command,
+}
+
+/// Commands a client can send to the server.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Command {
+    /// Subscribe to the messages matching the given subscription.
+    Subscribe(Subscription),
+    /// Remove the subscription with the given id.
+    Unsubscribe(u64),
+    /// List the monitored repositories and their branches.
+    ListRepos,
+    /// Get the latest messages, optionally for a single repository.
+    Status(Option<String>),
+    /// Trigger a check now for a repository, optionally for a single branch.
+    Check(String, Option<String>),
+    /// Authenticate with a shared token.
+    Authenticate(String),
+    /// Check the server is alive.  A client that pings is expected to keep
+    /// pinging, and is dropped once it goes silent for the heartbeat timeout.
+    Ping,
+}
+
+/// A subscription to a subset of the monitor messages.
+///
+/// A client with no subscriptions receives every message.  A message must
+/// match every non-empty list in a subscription to be sent.
+#[derive(Clone, Debug, Default, Deserialize, Getters, PartialEq, Serialize, Setters)]
+#[serde(default)]
+pub struct Subscription {
+    /// The repository name globs to receive messages for (all if empty).
+    #[get = "pub"]
+    #[set = "pub"]
+    repos: Vec<String>,
+    /// The branch names to receive messages for (all if empty).
+    #[get = "pub"]
+    #[set = "pub"]
+    branches: Vec<String>,
+    /// The message categories to receive (all if empty).
+    #[get = "pub"]
+    #[set = "pub"]
+    categories: Vec<Category>,
+}
+
+/// The response to a client request.
+#[derive(Clone, Debug, Deserialize, Getters, Serialize)]
+pub struct Response {
+    /// The id of the request this is a response to.
+    #[get = "pub"]
+    id: u64,
+    /// The reply, or an error message.
+    #[get = "pub"]
+    result: ::std::result::Result<Reply, String>,
+}
+
+impl Response {
+    /// Create a new response for the given request id.
+    pub fn new(id: u64, result: ::std::result::Result<Reply, String>) -> Self {
+        Self { id, result }
+    }
+}
+
+/// Successful replies to client commands.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Reply {
+    /// The command succeeded with nothing to report.
+    Ok,
+    /// The id of a new subscription.
+    Subscribed(u64),
+    /// The monitored repositories, mapped to their monitored branches.
+    Repos(BTreeMap<String, Vec<String>>),
+    /// The latest message for each monitored repository/branch.
+    Status(Vec<Message>),
+    /// The answer to a ping.
+    Pong,
+}
+
+/// A frame or request, tagged with the protocol version of its schema.
+#[derive(Clone, Debug, Deserialize, Getters, PartialEq, Serialize)]
+pub struct Envelope<T> {
+    /// The protocol version the body is serialized with.
+    #[get = "pub"]
+    version: u8,
+    /// The wrapped frame or request.
+    #[get = "pub"]
+    body: T,
+}
+
+impl<T> Envelope<T> {
+    /// Wrap the given body.
+    pub fn new(version: u8, body: T) -> Self {
+        Self { version, body }
+    }
+
+    /// Unwrap the body.
+    pub fn into_body(self) -> T {
+        self.body
+    }
+}
+
+/// A frame as serialized for a negotiated protocol version.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum Versioned<T> {
+    /// The bare frame, for versions before `ENVELOPE_VERSION`.
+    Bare(T),
+    /// The frame wrapped in an envelope.
+    Enveloped(Envelope<T>),
+}
+
+impl<T> Versioned<T> {
+    /// Wrap the given frame as the given protocol version expects it.
+    pub fn new(version: u8, frame: T) -> Self {
+        if version >= ENVELOPE_VERSION {
+            Versioned::Enveloped(Envelope::new(version, frame))
+        } else {
+            Versioned::Bare(frame)
+        }
+    }
+}
+
+/// A monitor message, with the structured status behind it.
+#[derive(Clone, Debug, Deserialize, Getters, Serialize)]
+pub struct StatusEvent {
+    /// The monitor message.
+    #[get = "pub"]
+    message: Message,
+    /// The status of the branch the message is for.
+    #[get = "pub"]
+    status: BranchStatus,
+    /// The change to the branches on a remote the message reports, if any.
+    #[get = "pub"]
+    change: Option<RefChange>,
+}
+
+impl StatusEvent {
+    /// Create a new status event.
+    pub fn new(message: Message, status: BranchStatus, change: Option<RefChange>) -> Self {
+        Self {
+            message,
+            status,
+            change,
+        }
+    }
+
+    /// Unwrap the monitor message.
+    pub fn into_message(self) -> Message {
+        self.message
+    }
+}
+
+/// Frames sent from the server to a client.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum ServerFrame {
+    /// The latest message for each monitored repository/branch, sent on connect.
+    Snapshot(Vec<Message>),
+    /// A monitor message, for versions before `STATUS_VERSION`.
+    Event(Message),
+    /// The response to a client request.
+    Response(Response),
+    /// Sent every heartbeat interval, so a client can tell a quiet server from a dead one.
+    Heartbeat,
+    /// The last frame sent before the server shuts down.
+    Shutdown,
+    /// A monitor message, with the status, or branch change, behind it.
+    StatusEvent(Box<StatusEvent>),
+}
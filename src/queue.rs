@@ -0,0 +1,267 @@
+// Copyright (c) 2017 repomons developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `repomons` bounded client queues.
+//!
+//! Every client is sent its frames through a queue holding at most a
+//! configured number of them, so a stalled client can't make the server grow
+//! without limit.  The overflow policy decides what happens when a queue is
+//! full.  The queues are only used on the event loop thread.
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll, Stream};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+/// What to do when a client queue is full.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Overflow {
+    /// Drop the oldest queued item to make room for the new one.
+    #[default]
+    DropOldest,
+    /// Drop the new item.
+    DropNewest,
+    /// Disconnect the client.
+    Disconnect,
+}
+
+/// The outcome of queueing an item.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sent {
+    /// The item was queued.
+    Queued,
+    /// The queue was full, so an item was dropped.  This is the total number
+    /// of items dropped from the queue.
+    Dropped(u64),
+}
+
+/// The reason an item could not be queued.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SendError {
+    /// The receiver has gone away.
+    Closed,
+    /// The queue was full, and the client should be disconnected.
+    Full,
+}
+
+/// The state shared by both ends of a queue.
+struct Shared<T> {
+    /// The queued items, oldest first.
+    items: VecDeque<T>,
+    /// The maximum number of queued items.
+    capacity: usize,
+    /// What to do when the queue is full.
+    overflow: Overflow,
+    /// The number of items dropped.
+    dropped: u64,
+    /// Is the sender still around?
+    sender: bool,
+    /// Is the receiver still around?
+    receiver: bool,
+    /// The task waiting for an item.
+    receiver_task: Option<Task>,
+    /// The tasks waiting for the sender to go away.
+    closed_tasks: Vec<Task>,
+}
+
+/// Create a queue holding at most `capacity` items.
+pub fn channel<T>(capacity: usize, overflow: Overflow) -> (Sender<T>, Receiver<T>) {
+    let shared = Rc::new(RefCell::new(Shared {
+        items: VecDeque::new(),
+        capacity: capacity.max(1),
+        overflow,
+        dropped: 0,
+        sender: true,
+        receiver: true,
+        receiver_task: None,
+        closed_tasks: Vec::new(),
+    }));
+
+    (
+        Sender {
+            shared: Rc::clone(&shared),
+        },
+        Receiver { shared },
+    )
+}
+
+/// The sending end of a queue.
+pub struct Sender<T> {
+    /// The shared queue state.
+    shared: Rc<RefCell<Shared<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Queue an item, applying the overflow policy if the queue is full.
+    pub fn send(&self, item: T) -> Result<Sent, SendError> {
+        let mut shared = self.shared.borrow_mut();
+        if !shared.receiver {
+            return Err(SendError::Closed);
+        }
+
+        let sent = if shared.items.len() < shared.capacity {
+            shared.items.push_back(item);
+            Sent::Queued
+        } else {
+            match shared.overflow {
+                Overflow::DropOldest => {
+                    shared.items.pop_front();
+                    shared.items.push_back(item);
+                }
+                Overflow::DropNewest => {}
+                Overflow::Disconnect => return Err(SendError::Full),
+            }
+            shared.dropped += 1;
+            Sent::Dropped(shared.dropped)
+        };
+
+        if let Some(task) = shared.receiver_task.take() {
+            task.notify();
+        }
+        Ok(sent)
+    }
+
+    /// The number of queued items.
+    pub fn queued(&self) -> usize {
+        self.shared.borrow().items.len()
+    }
+
+    /// The number of items dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.shared.borrow().dropped
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.borrow_mut();
+        shared.sender = false;
+        if let Some(task) = shared.receiver_task.take() {
+            task.notify();
+        }
+        for task in shared.closed_tasks.drain(..) {
+            task.notify();
+        }
+    }
+}
+
+/// The receiving end of a queue, a stream of the queued items that ends once
+/// the sender has gone away and the queue is empty.
+pub struct Receiver<T> {
+    /// The shared queue state.
+    shared: Rc<RefCell<Shared<T>>>,
+}
+
+impl<T> Receiver<T> {
+    /// A future that resolves once the sender has gone away, e.g. when the
+    /// client is disconnected.
+    pub fn closed(&self) -> Closed<T> {
+        Closed {
+            shared: Rc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<T>, ()> {
+        let mut shared = self.shared.borrow_mut();
+        match shared.items.pop_front() {
+            Some(item) => Ok(Async::Ready(Some(item))),
+            None if !shared.sender => Ok(Async::Ready(None)),
+            None => {
+                shared.receiver_task = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.borrow_mut();
+        shared.receiver = false;
+        shared.items.clear();
+    }
+}
+
+/// A future that resolves once the sender of a queue has gone away.
+pub struct Closed<T> {
+    /// The shared queue state.
+    shared: Rc<RefCell<Shared<T>>>,
+}
+
+impl<T> Future for Closed<T> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        let mut shared = self.shared.borrow_mut();
+        if !shared.sender {
+            return Ok(Async::Ready(()));
+        }
+        if !shared
+            .closed_tasks
+            .iter()
+            .any(|task| task.will_notify_current())
+        {
+            shared.closed_tasks.push(task::current());
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{channel, Overflow, SendError, Sent};
+    use futures::{Future, Stream};
+
+    #[test]
+    fn drop_oldest() {
+        let (tx, rx) = channel(2, Overflow::DropOldest);
+        assert_eq!(tx.send(1), Ok(Sent::Queued));
+        assert_eq!(tx.send(2), Ok(Sent::Queued));
+        assert_eq!(tx.send(3), Ok(Sent::Dropped(1)));
+        assert_eq!(tx.send(4), Ok(Sent::Dropped(2)));
+        assert_eq!(tx.queued(), 2);
+        drop(tx);
+        assert_eq!(rx.wait().collect::<Result<Vec<u8>, ()>>(), Ok(vec![3, 4]));
+    }
+
+    #[test]
+    fn drop_newest() {
+        let (tx, rx) = channel(2, Overflow::DropNewest);
+        for i in 1..5 {
+            assert!(tx.send(i).is_ok());
+        }
+        assert_eq!(tx.dropped(), 2);
+        drop(tx);
+        assert_eq!(rx.wait().collect::<Result<Vec<u8>, ()>>(), Ok(vec![1, 2]));
+    }
+
+    #[test]
+    fn disconnect() {
+        let (tx, rx) = channel(1, Overflow::Disconnect);
+        let closed = rx.closed();
+        assert_eq!(tx.send(1), Ok(Sent::Queued));
+        assert_eq!(tx.send(2), Err(SendError::Full));
+        drop(tx);
+        assert_eq!(closed.wait(), Ok(()));
+        assert_eq!(rx.wait().collect::<Result<Vec<u8>, ()>>(), Ok(vec![1]));
+    }
+
+    #[test]
+    fn receiver_gone() {
+        let (tx, rx) = channel(1, Overflow::DropOldest);
+        drop(rx);
+        assert_eq!(tx.send(1), Err(SendError::Closed));
+    }
+}
@@ -0,0 +1,115 @@
+// Copyright (c) 2017 repomons developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `repomons` configuration reload.
+//!
+//! The configuration file is reloaded on SIGHUP, or when its modification
+//! time changes, and the monitors are updated to match without dropping any
+//! clients.  Only the repositories, and their `[repos.<name>]` settings, are
+//! reloaded; the `[server]` settings still need a restart.
+use config::Config;
+use error::{Error, Result};
+use futures::{Future, Stream};
+use log::Logs;
+use monitors::Monitors;
+use repomon;
+use std::fs::{self, File};
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio_core::reactor::{Handle, Interval};
+
+/// How often to check the configuration file for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Reload the configuration at the given path into the monitors on SIGHUP,
+/// or when the file changes.
+pub fn serve(
+    path: PathBuf,
+    mut monitors: Monitors,
+    handle: &Handle,
+    logs: &Logs,
+) -> Result<Box<dyn Future<Item = (), Error = ()>>> {
+    let mut last_modified = modified(&path);
+    let poll_path = path.clone();
+    let changes = Interval::new(POLL_INTERVAL, handle)?
+        .map_err(Error::from)
+        .filter_map(move |_| {
+            let modified = modified(&poll_path);
+            if modified == last_modified {
+                None
+            } else {
+                last_modified = modified;
+                Some("file changed")
+            }
+        });
+
+    let reload_logs = logs.clone();
+    let reloads = hangups(handle).select(changes).for_each(move |reason| {
+        try_info!(reload_logs.stdout(), "Reloading configuration"; "reason" => reason);
+        match load(&path).and_then(|(repomon, config)| monitors.apply(&repomon, &config)) {
+            Ok(diff) => {
+                try_info!(
+                    reload_logs.stdout(),
+                    "Configuration reloaded";
+                    "added" => diff.added().len(),
+                    "removed" => diff.removed().len(),
+                    "changed" => diff.changed().len()
+                );
+            }
+            Err(e) => {
+                try_error!(
+                    reload_logs.stderr(),
+                    "Invalid configuration, keeping the current one: {}",
+                    e
+                )
+            }
+        }
+        Ok(())
+    });
+
+    let error_logs = logs.clone();
+    Ok(Box::new(reloads.map_err(move |e| {
+        try_error!(error_logs.stderr(), "Configuration reload stopped: {}", e)
+    })))
+}
+
+/// The modification time of the file at the given path.
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Read the repository configuration, along with the `repomons` specific
+/// repository settings, at the given path.
+fn load(path: &Path) -> Result<(repomon::Repomon, Config)> {
+    let mut toml = String::new();
+    let _ = File::open(path)?.read_to_string(&mut toml)?;
+    Ok((
+        repomon::read_toml(&mut Cursor::new(&toml))?,
+        Config::from_toml(&toml)?,
+    ))
+}
+
+/// A stream of the SIGHUPs received.
+#[cfg(unix)]
+fn hangups(handle: &Handle) -> Box<dyn Stream<Item = &'static str, Error = Error>> {
+    use tokio_signal::unix::{Signal, SIGHUP};
+
+    Box::new(
+        Signal::with_handle(SIGHUP, handle.new_tokio_handle())
+            .flatten_stream()
+            .map(|_| "SIGHUP")
+            .map_err(Error::from),
+    )
+}
+
+/// There are no SIGHUPs off Unix platforms.
+#[cfg(not(unix))]
+fn hangups(_handle: &Handle) -> Box<dyn Stream<Item = &'static str, Error = Error>> {
+    Box::new(::futures::stream::empty())
+}
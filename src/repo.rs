@@ -14,33 +14,52 @@ use git2::{FetchOptions, ProxyOptions, Repository};
 use repomon::Remote;
 use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 use term;
 
 /// Repository config.
 #[derive(Clone, Debug, Default, Getters, Setters)]
 pub struct Config<'a> {
-    /// The base directory to start repository discovery/clone at.
+    /// The absolute path to discover/clone the repository at.
     #[get = "pub"]
     #[set = "pub"]
-    basedir: PathBuf,
-    /// The repository name.
-    #[get = "pub"]
-    #[set = "pub"]
-    repo: PathBuf,
+    path: PathBuf,
     /// The set of remotes to fetch.
     #[get = "pub"]
     #[set = "pub"]
     remotes: &'a [Remote],
+    /// Set to cancel a clone in progress.
+    #[get = "pub"]
+    #[set = "pub"]
+    cancelled: Arc<AtomicBool>,
+}
+
+/// The absolute path of a repository: the given path, or the repository
+/// name, under the base directory.  A relative base directory is relative to
+/// the working directory.
+pub fn path(basedir: &Path, repo: &str, path: Option<&Path>) -> Result<PathBuf> {
+    let path = basedir.join(path.unwrap_or_else(|| Path::new(repo)));
+    let path = if path.is_absolute() {
+        path
+    } else {
+        env::current_dir()?.join(path)
+    };
+    Ok(path
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .collect())
 }
 
-/// Discover the given repository at the given base directory, to try to clone it there.
+/// Discover the given repository at its path, to try to clone it there.
 pub fn discover_or_clone(config: &Config) -> Result<Repository> {
-    if fs::metadata(config.basedir()).is_err() {
-        fs::create_dir(config.basedir())?;
+    if let Some(parent) = config.path().parent() {
+        if fs::metadata(parent).is_err() {
+            fs::create_dir_all(parent)?;
+        }
     }
-    env::set_current_dir(config.basedir())?;
-    match Repository::discover(config.repo()) {
+    match Repository::discover(config.path()) {
         Ok(repository) => Ok(repository),
         Err(_e) => {
             let origin: &Remote = config
@@ -52,7 +71,8 @@ pub fn discover_or_clone(config: &Config) -> Result<Repository> {
             let mut repo_builder = RepoBuilder::new();
 
             let mut t = term::stdout().ok_or("unable to create stdout term")?;
-            let clone_output: CallbackOutput = Default::default();
+            let mut clone_output: CallbackOutput = Default::default();
+            clone_output.set_cancelled(Arc::clone(config.cancelled()));
             let remote_callbacks = callbacks::get_default(clone_output)?;
 
             let mut proxy_opts = ProxyOptions::new();
@@ -64,8 +84,8 @@ pub fn discover_or_clone(config: &Config) -> Result<Repository> {
 
             repo_builder.fetch_options(fetch_opts);
 
-            writeln!(t, "Cloning into '{}'...", config.repo().display())?;
-            let repo = match repo_builder.clone(origin.url(), config.repo().as_ref()) {
+            writeln!(t, "Cloning into '{}'...", config.path().display())?;
+            let repo = match repo_builder.clone(origin.url(), config.path()) {
                 Ok(repository) => repository,
                 Err(e) => return Err(format!("Unable to clone repository: {}", e).into()),
             };
@@ -101,3 +121,30 @@ pub fn check_remotes(repo: &Repository, config: &Config) -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn path() {
+        let basedir = Path::new("/home/jozias/projects");
+        assert_eq!(
+            super::path(basedir, "ar2", None).expect(""),
+            PathBuf::from("/home/jozias/projects/ar2")
+        );
+        assert_eq!(
+            super::path(basedir, "ar2", Some(Path::new("/srv/git/ar2"))).expect(""),
+            PathBuf::from("/srv/git/ar2")
+        );
+        assert_eq!(
+            super::path(basedir, "ar2", Some(Path::new("mirrors/ar2"))).expect(""),
+            PathBuf::from("/home/jozias/projects/mirrors/ar2")
+        );
+        assert_eq!(
+            super::path(Path::new("./projects"), "ar2", None).expect(""),
+            env::current_dir().expect("").join("projects/ar2")
+        );
+    }
+}
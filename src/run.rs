@@ -7,25 +7,37 @@
 // modified, or distributed except according to those terms.
 
 //! `repomon` runtime
-use branch::{self, MonitorConfig};
+use auth::Token;
+use branch::MonitorConfig;
 use clap::{App, Arg};
+use config::{Config, TlsConfig};
 use error::Result;
+use event::Update;
+use futures::future::{self, Either};
 use futures::sync::mpsc;
 use futures::{Future, Stream};
+use heartbeat;
+use http;
+use listen::{self, Address};
 use log::Logs;
+use monitors::Monitors;
+use queue::{SendError, Sent};
+use reload;
 use repomon;
+use scheduler::Scheduler;
+use server::Server;
+use shutdown::{self, Shutdown};
 use slog::Level;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{Cursor, Read};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::rc::Rc;
-use std::thread;
-use tokio_core::net::TcpListener;
+use tls;
 use tokio_core::reactor::Core;
-use tokio_io::io::write_all;
-use tokio_io::AsyncRead;
+use ws;
 
 /// CLI Runtime
 pub fn run() -> Result<i32> {
@@ -46,8 +58,44 @@ pub fn run() -> Result<i32> {
                 .short("a")
                 .long("address")
                 .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
                 .required(true)
-                .default_value("127.0.0.1:8080"),
+                .default_value("127.0.0.1:8080")
+                .help("Listen for monitor clients on the given address, or Unix socket with unix:<path>"),
+        )
+        .arg(
+            Arg::with_name("http")
+                .long("http")
+                .takes_value(true)
+                .help("Serve the HTTP/JSON status API on the given address"),
+        )
+        .arg(
+            Arg::with_name("ws")
+                .long("ws")
+                .takes_value(true)
+                .help("Serve the WebSocket transport on the given address"),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .long("tls-cert")
+                .takes_value(true)
+                .requires("tls-key")
+                .help("Serve the monitor listener over TLS with the given PEM certificate chain"),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .long("tls-key")
+                .takes_value(true)
+                .requires("tls-cert")
+                .help("The PEM private key for the TLS certificate"),
+        )
+        .arg(
+            Arg::with_name("tls-client-ca")
+                .long("tls-client-ca")
+                .takes_value(true)
+                .requires("tls-cert")
+                .help("Only accept TLS clients with a certificate signed by a CA in the given PEM file"),
         )
         .arg(
             Arg::with_name("verbose")
@@ -83,131 +131,207 @@ pub fn run() -> Result<i32> {
     let mut logs: Logs = Default::default();
     logs.set_stdout_level(level);
 
-    // Logging clones for server, monitor threads, receiver, and config.
-    let server_logs = logs.clone();
-    let thread_logs = logs.clone();
+    // Logging clones for server, branch checks, receiver, and config.
     let receiver_logs = logs.clone();
     let config_logs = logs.clone();
     let core_logs = logs.clone();
 
     try_trace!(logs.stdout(), "Logging configured!");
 
-    let config_file = File::open(matches.value_of("config").ok_or("invalid config file")?)?;
-    let mut reader = BufReader::new(config_file);
-    let repomon = repomon::read_toml(&mut reader)?;
+    let config_path = matches.value_of("config").ok_or("invalid config file")?;
+    let mut config_file = File::open(config_path)?;
+    let mut toml = String::new();
+    config_file.read_to_string(&mut toml)?;
+    let repomon = repomon::read_toml(&mut Cursor::new(&toml))?;
+    let mut config = Config::from_toml(&toml)?;
+
+    // The TLS settings on the command line replace those in the TOML.
+    if let (Some(cert), Some(key)) = (matches.value_of("tls-cert"), matches.value_of("tls-key")) {
+        let mut tls_config: TlsConfig = Default::default();
+        tls_config.set_cert(cert.into());
+        tls_config.set_key(key.into());
+        tls_config.set_client_ca(matches.value_of("tls-client-ca").map(Into::into));
+        let mut server_config = config.server().clone();
+        server_config.set_tls(Some(tls_config));
+        config.set_server(server_config);
+    }
 
     try_trace!(logs.stdout(), "Configuration TOML parsed!");
 
-    let addr = matches
-        .value_of("address")
-        .ok_or("invalid address")?
-        .parse::<SocketAddr>()?;
+    let mut addresses = Vec::new();
+    for address in matches.values_of("address").ok_or("invalid address")? {
+        addresses.push(address.parse::<Address>()?);
+    }
     let mut core = Core::new()?;
     let remote_handle = core.remote();
     let handle = core.handle();
-    let socket = TcpListener::bind(&addr, &handle)?;
-    try_trace!(logs.stdout(), "Listening for connections"; "addr" => format!("{}", addr));
 
     // This is a single-threaded server, so we can just use Rc and RefCell to
-    // store the map of all connections we know about.
-    let connections = Rc::new(RefCell::new(HashMap::new()));
-
-    // Clone some conns for the worker and for the server to reference.
-    let rx_cons = Rc::clone(&connections);
-    let srv_cons = Rc::clone(&connections);
-
-    let srv = socket.incoming().for_each(move |(stream, addr)| {
-        try_trace!(server_logs.stdout(), "Connection opened"; "addr" => format!("{}", addr));
-        // We currently don't accept input from the clients, so only grabbing the writer.
-        let (_r, writer) = stream.split();
-
-        // Create a channel for our stream, which other sockets will use to
-        // send us messages. Then register our address with the stream to send
-        // data to us.
-        let (tx, rx) = mpsc::unbounded();
-        connections.borrow_mut().insert(addr, tx);
-
-        // Whenever we receive a string on the Receiver, we write it to
-        // `WriteHalf<TcpStream>`.
-        let writer_logs = logs.clone();
-        let socket_writer = rx.fold(writer, move |writer, msg: Vec<u8>| {
-            try_trace!(writer_logs.stdout(), "Sending bincoded monitor"; "addr" => format!("{}", addr));
-            let writer_buf_tuple = write_all(writer, msg);
-            let writer = writer_buf_tuple.map(|(writer, _)| writer);
-            writer.map_err(|_| ())
-        });
-
-        // Make the socket write future into a future that can be spawned.
-        let socket_writer = socket_writer.map(|_| ());
-
-        let connections = Rc::clone(&srv_cons);
-        let spawn_logs = server_logs.clone();
-        handle.spawn(socket_writer.then(move |_| {
-            try_trace!(spawn_logs.stdout(), "Closing connection"; "addr" => format!("{}", addr));
-            connections.borrow_mut().remove(&addr);
-            Ok(())
-        }));
+    // store the server state, including the map of all connections we know about.
+    let mut tokens = Vec::new();
+    for token in config.server().tokens() {
+        tokens.push(Token::try_from(token)?);
+    }
+    let mut server = Server::default();
+    server.set_auth(tokens, config.server().auth_timeout());
+    server.set_queue(*config.server().queue());
+    server.set_heartbeat(*config.server().heartbeat());
+    let server = Rc::new(RefCell::new(server));
 
-        Ok(())
-    });
+    heartbeat::serve(&handle, Rc::clone(&server), &logs)?;
 
-    let srv = srv.map_err(|_| ());
+    if let Some(http_addr) = matches.value_of("http") {
+        http::serve(
+            &http_addr.parse::<SocketAddr>()?,
+            &handle,
+            Rc::clone(&server),
+            &logs,
+        )?;
+    }
 
-    // The tx gets cloned into monitor threads for sending messages.
-    // The rx send received messages to connected clients.
-    let (tx, rx) = mpsc::unbounded();
-    let basedir = repomon.basedir();
-
-    let mut monitor_config = MonitorConfig::new(basedir, tx, config_logs, remote_handle);
-
-    // Startup the monitor threads (one per repository/branch combination).
-    for (repo_name, repo) in repomon.repos() {
-        for branch in repo.branch() {
-            // All the clones.  Moving into monitor thread.
-            let t_logs = thread_logs.clone();
-            let t_repo_name = repo_name.clone();
-            let t_branch_name = branch.name().clone();
-            monitor_config.set_repo_name(repo_name.clone());
-            monitor_config.set_branch(branch.clone());
-            monitor_config.set_remotes(repo.remotes().clone());
-
-            let t_monitor_config = monitor_config.clone();
-
-            thread::spawn(move || {
-                if let Err(e) = branch::monitor(&t_monitor_config) {
-                    try_error!(
-                        t_logs.stderr(),
-                        "Error starting monitor: {}", e;
-                        "repository" => t_repo_name,
-                        "branch" => t_branch_name
-                    );
-                }
-            });
+    if let Some(ws_addr) = matches.value_of("ws") {
+        ws::serve(
+            &ws_addr.parse::<SocketAddr>()?,
+            &handle,
+            Rc::clone(&server),
+            &logs,
+        )?;
+    }
+
+    // Clone some state for the worker and for the monitors to reference.
+    let rx_server = Rc::clone(&server);
+
+    let acceptor = match *config.server().tls() {
+        Some(ref tls_config) => {
+            try_trace!(logs.stdout(), "TLS enabled"; "client_verification" => tls_config.client_ca().is_some());
+            Some(tls::acceptor(tls_config)?)
         }
+        None => None,
+    };
+
+    let mut listeners = Vec::new();
+    for address in &addresses {
+        listeners.push(listen::serve(
+            address,
+            &handle,
+            &server,
+            acceptor.as_ref(),
+            &logs,
+        )?);
     }
+    let srv = future::join_all(listeners).map(|_| ());
+
+    // The tx gets cloned into the branch checks for sending messages.
+    // The rx send received messages to connected clients.
+    let (tx, rx) = mpsc::unbounded();
+
+    let shutdown_state: Shutdown = Default::default();
+    let mut monitor_config =
+        MonitorConfig::new(tx, config_logs, remote_handle, shutdown_state.clone());
+    monitor_config.set_events(*config.server().events());
+
+    // The scheduler thread, and every check in flight, holds a clone of
+    // `running` until it is done.
+    let (running, stopped) = mpsc::unbounded::<()>();
+    let scheduler = Scheduler::start(
+        *config.server().workers(),
+        running.clone(),
+        shutdown_state.clone(),
+        logs.clone(),
+    );
+
+    // Schedule the monitors (one per repository/branch combination), and
+    // restart them as the configuration changes.
+    let mut monitors = Monitors::new(
+        monitor_config,
+        Rc::clone(&server),
+        scheduler.clone(),
+        logs.clone(),
+    );
+    monitors.apply(&repomon, &config)?;
+    let reloads = reload::serve(PathBuf::from(config_path), monitors, &handle, &logs)?;
 
     // This is where we send messages from the monitors off to any connected clients.
-    let rx_fut = rx.for_each(|message_result| {
-        match message_result {
-            Ok(message) => {
-                let mut conns = rx_cons.borrow_mut();
-                for tx in conns.iter_mut().map(|(_, v)| v) {
-                    if tx.unbounded_send(message.clone()).is_err() {
-                        try_error!(receiver_logs.stderr(), "Error sending message");
+    let rx_fut = rx.for_each(|update_result| {
+        let events = match update_result {
+            Ok(Update::Matched(repo, pattern, branches)) => {
+                let (added, retired) = rx_server.borrow_mut().set_matched(&repo, &pattern, branches);
+                for branch in added {
+                    try_info!(receiver_logs.stdout(), "Branch matched"; "repository" => &repo, "branch" => branch, "pattern" => &pattern);
+                }
+                for branch in retired {
+                    try_info!(receiver_logs.stdout(), "Branch retired"; "repository" => &repo, "branch" => branch, "pattern" => &pattern);
+                }
+                Vec::new()
+            }
+            Ok(Update::Refs(repo, remote, refs)) => {
+                let changes = rx_server.borrow_mut().set_refs(&repo, &remote, refs);
+                changes
+                    .iter()
+                    .map(|change| {
+                        try_info!(receiver_logs.stdout(), "{}", change.message(&remote); "repository" => &repo);
+                        change.event(&repo, &remote)
+                    })
+                    .collect()
+            }
+            // Drop the last event of a monitor stopped by a reload.
+            Ok(Update::Event(ref event))
+                if !rx_server
+                    .borrow()
+                    .is_monitored(event.status().repo(), event.status().branch()) =>
+            {
+                Vec::new()
+            }
+            Ok(Update::Event(event)) => vec![event],
+            Err(()) => {
+                try_error!(receiver_logs.stderr(), "Error");
+                Vec::new()
+            }
+        };
+
+        for event in events {
+            for (addr, result) in rx_server.borrow_mut().publish(&event) {
+                match result {
+                    Ok(Sent::Dropped(dropped)) => {
+                        try_warn!(receiver_logs.stdout(), "Client queue full, message dropped"; "addr" => format!("{}", addr), "dropped" => dropped)
+                    }
+                    Err(SendError::Full) => {
+                        try_warn!(receiver_logs.stdout(), "Client queue full, disconnecting"; "addr" => format!("{}", addr))
+                    }
+                    _ => {
+                        try_error!(receiver_logs.stderr(), "Error sending message"; "addr" => format!("{}", addr))
                     }
                 }
             }
-            Err(()) => try_error!(receiver_logs.stderr(), "Error"),
         }
         Ok(())
     });
 
-    // Join the server and monitor futures.
-    let both = rx_fut.join(srv);
+    // Join the server, monitor and reload futures.
+    let both = rx_fut.join(srv).join(reloads);
 
     try_info!(core_logs.stdout(), "Starting repomons...");
-    // Run the monitors and the server.
-    core.run(both).expect("Failed to run event loop");
+    // Run the monitors and the server until we are asked to stop.
+    match core.run(both.select2(shutdown::signal(&handle))) {
+        Ok(Either::A(_)) => {}
+        Ok(Either::B((signal, _))) => {
+            try_info!(core_logs.stdout(), "Shutting down..."; "signal" => signal)
+        }
+        Err(Either::A(_)) => return Err("Failed to run event loop".into()),
+        Err(Either::B((e, _))) => return Err(e),
+    }
+    drop(running);
+
+    shutdown::run(
+        &mut core,
+        &server,
+        &scheduler,
+        &shutdown_state,
+        stopped,
+        config.server().shutdown_timeout(),
+        &core_logs,
+    )?;
+    try_info!(core_logs.stdout(), "repomons stopped");
 
     Ok(0)
 }
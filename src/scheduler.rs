@@ -0,0 +1,669 @@
+// Copyright (c) 2017 repomons developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `repomons` branch check scheduler.
+//!
+//! A single scheduler thread keeps a priority queue of the next check due for
+//! every monitored repository/branch pair, and runs the due checks on a
+//! bounded pool of worker threads.  The first check of a branch is delayed by
+//! up to 80% of its interval, each following check is due an interval after
+//! the previous one finished, and the checks of a repository never overlap.
+//! The branches of a repository that are due are checked together, sharing a
+//! single fetch of each remote, while the rest keep their own interval.  A check that fails, or panics,
+//! still releases its repository for the next check.
+use branch::{self, MonitorConfig};
+use error::Result;
+use futures::sync::mpsc::UnboundedSender;
+use futures_cpupool::{Builder, CpuPool};
+use log::Logs;
+use monitors::Key;
+use rand::{self, Rng};
+use shutdown::Shutdown;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashSet};
+use std::mem;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The checks of a repository run together, with the key and generation of
+/// each branch checked.
+type Batch<T> = Vec<(Key, u64, T)>;
+
+/// Messages sent to the scheduler thread.
+enum Message {
+    /// Start checking a repository/branch pair.
+    Add(Key, Box<MonitorConfig>),
+    /// Run a check of a repository/branch pair now.
+    Check(Key),
+    /// Stop checking a repository/branch pair.
+    Remove(Key),
+    /// The check of the given repository branches finished.
+    Done(String, Vec<(Key, u64)>),
+    /// Stop the scheduler thread, once the server is shutting down.
+    Stop,
+}
+
+/// The check state of a repository/branch pair.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    /// The next check is due at the given time.
+    Due(Instant),
+    /// Due, but waiting for the running check of the repository to finish.
+    Waiting,
+    /// Being checked.
+    Running,
+}
+
+/// A scheduled repository/branch pair.
+struct Entry<T> {
+    /// What the check runs with.
+    value: T,
+    /// The time between the end of a check and the start of the next one.
+    interval: Duration,
+    /// Tells this entry apart from a removed one with the same key.
+    generation: u64,
+    /// The check state.
+    state: State,
+    /// Was a check requested while one was running?
+    requested: bool,
+}
+
+/// The priority queue of due checks.
+///
+/// Once a branch is due, every due branch of its repository is checked with
+/// it, so each remote is only fetched once.  Each of those branches is then
+/// due an interval later.
+struct Queue<T> {
+    /// The scheduled entries.
+    entries: BTreeMap<Key, Entry<T>>,
+    /// The due times, earliest first.  Times that no longer match their
+    /// entry are skipped when they come up.
+    due: BinaryHeap<Reverse<(Instant, Key)>>,
+    /// The repositories being checked.
+    busy: HashSet<String>,
+    /// The generation of the next entry.
+    next_generation: u64,
+}
+
+impl<T: Clone> Queue<T> {
+    /// Create an empty queue.
+    fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            due: BinaryHeap::new(),
+            busy: HashSet::new(),
+            next_generation: 0,
+        }
+    }
+
+    /// Schedule the first check of a pair, replacing any previous entry.
+    fn add(&mut self, key: Key, value: T, interval: Duration, due: Instant) {
+        let generation = self.next_generation;
+        self.next_generation += 1;
+        let _ = self.entries.insert(
+            key.clone(),
+            Entry {
+                value,
+                interval,
+                generation,
+                state: State::Due(due),
+                requested: false,
+            },
+        );
+        self.schedule(key, due);
+    }
+
+    /// Remove a pair.  A check in flight is left to finish.
+    fn remove(&mut self, key: &Key) {
+        let _ = self.entries.remove(key);
+    }
+
+    /// Move the next check of a pair to now, or once the running check
+    /// finishes.
+    fn check(&mut self, key: &Key, now: Instant) {
+        let due = match self.entries.get_mut(key) {
+            Some(entry) => match entry.state {
+                State::Due(_) => true,
+                State::Running => {
+                    entry.requested = true;
+                    false
+                }
+                State::Waiting => false,
+            },
+            None => false,
+        };
+        if due {
+            self.schedule(key.clone(), now);
+        }
+    }
+
+    /// Schedule the next checks of a repository once the check of the given
+    /// branches has finished.
+    fn done(&mut self, repo: &str, checked: &[(Key, u64)], now: Instant) {
+        let _ = self.busy.remove(repo);
+        for (key, generation) in checked {
+            let due = match self.entries.get_mut(key) {
+                Some(entry) if entry.generation == *generation => {
+                    if entry.requested {
+                        entry.requested = false;
+                        now
+                    } else {
+                        now + entry.interval
+                    }
+                }
+                _ => continue,
+            };
+            self.schedule(key.clone(), due);
+        }
+
+        // Run the checks that came due in the meantime.
+        let waiting: Vec<Key> = self
+            .entries
+            .iter()
+            .filter(|(key, entry)| key.0 == repo && entry.state == State::Waiting)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in waiting {
+            self.schedule(key, now);
+        }
+    }
+
+    /// Pop the due checks of a repository with a branch due at the given time,
+    /// marking them running.
+    fn pop(&mut self, now: Instant) -> Option<Batch<T>> {
+        while let Some(Reverse((due, _))) = self.due.peek().cloned() {
+            if due > now {
+                break;
+            }
+            let Reverse((due, key)) = self.due.pop()?;
+            match self.entries.get_mut(&key) {
+                Some(ref mut entry) if entry.state == State::Due(due) => {
+                    if self.busy.contains(&key.0) {
+                        entry.state = State::Waiting;
+                        continue;
+                    }
+                }
+                _ => continue,
+            }
+            return Some(self.start(&key.0, now));
+        }
+        None
+    }
+
+    /// Start checking the branches of a repository due at the given time.
+    fn start(&mut self, repo: &str, now: Instant) -> Batch<T> {
+        let _ = self.busy.insert(repo.to_string());
+        self.entries
+            .iter_mut()
+            .filter(|(key, entry)| match entry.state {
+                State::Due(due) => key.0 == repo && due <= now,
+                _ => false,
+            })
+            .map(|(key, entry)| {
+                entry.state = State::Running;
+                (key.clone(), entry.generation, entry.value.clone())
+            })
+            .collect()
+    }
+
+    /// When the next check is due.
+    fn next_due(&self) -> Option<Instant> {
+        self.due.peek().map(|Reverse((due, _))| *due)
+    }
+
+    /// Set when the next check of a pair is due.
+    fn schedule(&mut self, key: Key, due: Instant) {
+        if let Some(entry) = self.entries.get_mut(&key) {
+            entry.state = State::Due(due);
+            self.due.push(Reverse((due, key)));
+        }
+    }
+}
+
+/// The handle to the scheduler thread.
+#[derive(Clone)]
+pub struct Scheduler {
+    /// The scheduler thread messages.
+    tx: Sender<Message>,
+}
+
+/// The control for a scheduled repository/branch pair.
+#[derive(Clone)]
+pub struct Control {
+    /// The repository/branch pair.
+    key: Key,
+    /// The scheduler thread messages.
+    tx: Sender<Message>,
+}
+
+impl Control {
+    /// Run a check now, rather than waiting for the interval to pass.
+    pub fn check(&self) -> Result<()> {
+        self.tx
+            .send(Message::Check(self.key.clone()))
+            .map_err(|_| "scheduler stopped".into())
+    }
+
+    /// Stop checking the pair.  A check in flight is left to finish.
+    pub fn stop(&self) {
+        let _ = self.tx.send(Message::Remove(self.key.clone()));
+    }
+
+    /// A control for a pair no scheduler is checking.
+    #[cfg(test)]
+    pub fn detached(key: Key) -> Self {
+        Self {
+            key,
+            tx: channel().0,
+        }
+    }
+}
+
+/// The worker pool, and what every check run on it needs.
+struct Workers {
+    /// The worker threads.
+    pool: CpuPool,
+    /// Tells the scheduler thread a check is done.
+    done: Sender<Message>,
+    /// Held by every check in flight.
+    running: UnboundedSender<()>,
+    /// The server shutdown state.
+    shutdown: Shutdown,
+    /// The slog logs.
+    logs: Logs,
+}
+
+/// Tells the scheduler thread the checks of a repository are done when
+/// dropped, so a check that panics still releases the repository.
+struct Done {
+    /// Tells the scheduler thread a check is done.
+    done: Sender<Message>,
+    /// The repository checked.
+    repo: String,
+    /// The key and generation of each branch checked.
+    checked: Vec<(Key, u64)>,
+    /// Held until the check is done.
+    _running: UnboundedSender<()>,
+    /// The slog logs.
+    logs: Logs,
+}
+
+impl Drop for Done {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            try_error!(self.logs.stderr(), "Repository check panicked"; "repository" => &self.repo);
+        }
+        let _ = self.done.send(Message::Done(
+            mem::take(&mut self.repo),
+            mem::take(&mut self.checked),
+        ));
+    }
+}
+
+impl Workers {
+    /// Run the checks of a repository, telling the scheduler thread once
+    /// they are done.
+    fn spawn(&self, batch: Batch<MonitorConfig>) {
+        let done = self.done.clone();
+        let running = self.running.clone();
+        let shutdown = self.shutdown.clone();
+        let logs = self.logs.clone();
+
+        self.pool
+            .spawn_fn(move || {
+                let (checked, configs): (Vec<_>, Vec<_>) = batch
+                    .into_iter()
+                    .map(|(key, generation, config)| ((key, generation), config))
+                    .unzip();
+                let repo = checked
+                    .first()
+                    .map(|((repo, _), _)| repo.clone())
+                    .unwrap_or_default();
+                let done = Done {
+                    done,
+                    repo,
+                    checked,
+                    _running: running,
+                    logs,
+                };
+                match branch::check(&configs) {
+                    Ok(()) => {}
+                    // A fetch cancelled on shutdown.
+                    Err(_) if shutdown.is_stopping() => {}
+                    Err(e) => try_error!(
+                        done.logs.stderr(),
+                        "Error checking repository: {}", e;
+                        "repository" => &done.repo
+                    ),
+                }
+                drop(done);
+                Ok::<(), ()>(())
+            })
+            .forget();
+    }
+}
+
+impl Scheduler {
+    /// Start the scheduler thread, running at most `workers` checks at the
+    /// same time.  The scheduler thread, and every check in flight, holds a
+    /// clone of `running` until it is done.  The scheduler thread exits once
+    /// the server is shutting down.
+    pub fn start(
+        workers: usize,
+        running: UnboundedSender<()>,
+        shutdown: Shutdown,
+        logs: Logs,
+    ) -> Self {
+        let (tx, rx) = channel();
+        let workers = Workers {
+            pool: Builder::new()
+                .pool_size(workers.max(1))
+                .name_prefix("repomons-worker-")
+                .create(),
+            done: tx.clone(),
+            running,
+            shutdown,
+            logs,
+        };
+        let _ = thread::spawn(move || run(&rx, &workers));
+        Self { tx }
+    }
+
+    /// Start checking a repository/branch pair with the given configuration,
+    /// returning its control.
+    pub fn add(&self, key: Key, config: MonitorConfig) -> Control {
+        let _ = self.tx.send(Message::Add(key.clone(), Box::new(config)));
+        Control {
+            key,
+            tx: self.tx.clone(),
+        }
+    }
+
+    /// Stop the scheduler thread.  Checks in flight are left to finish.
+    pub fn stop(&self) {
+        let _ = self.tx.send(Message::Stop);
+    }
+}
+
+/// The scheduler thread.
+fn run(rx: &Receiver<Message>, workers: &Workers) {
+    let logs = &workers.logs;
+    let mut queue = Queue::new();
+    let mut rng = rand::thread_rng();
+
+    while !workers.shutdown.is_stopping() {
+        // Hand every due check to the worker pool.
+        while let Some(batch) = queue.pop(Instant::now()) {
+            workers.spawn(batch);
+        }
+
+        let message = match queue.next_due() {
+            Some(due) => rx.recv_timeout(due.saturating_duration_since(Instant::now())),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match message {
+            Ok(Message::Add(key, config)) => {
+                let interval = match config.branch().interval_to_ms() {
+                    Ok(interval) => interval as u64,
+                    Err(e) => {
+                        try_error!(logs.stderr(), "Invalid interval: {}", e; "repository" => &key.0, "branch" => &key.1);
+                        continue;
+                    }
+                };
+
+                // Delay the first check up to 80% to avoid running all the
+                // same intervals at the same time.
+                let max_delay = (interval * 4) / 5;
+                let rand_delay = if max_delay > 0 {
+                    rng.gen_range(0..max_delay)
+                } else {
+                    0
+                };
+                try_trace!(logs.stdout(), "Delaying first check"; "ms" => rand_delay, "repository" => &key.0, "branch" => &key.1);
+                queue.add(
+                    key,
+                    *config,
+                    Duration::from_millis(interval),
+                    Instant::now() + Duration::from_millis(rand_delay),
+                );
+            }
+            Ok(Message::Check(key)) => queue.check(&key, Instant::now()),
+            Ok(Message::Remove(key)) => queue.remove(&key),
+            Ok(Message::Done(repo, checked)) => queue.done(&repo, &checked, Instant::now()),
+            Ok(Message::Stop) | Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Done, Message, Queue, State};
+    use futures::sync::mpsc;
+    use log::Logs;
+    use monitors::Key;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn key(repo: &str, branch: &str) -> Key {
+        (repo.to_string(), branch.to_string())
+    }
+
+    fn keys<T>(batch: Option<Vec<(Key, u64, T)>>) -> Vec<Key> {
+        batch
+            .map(|batch| batch.into_iter().map(|(key, _, _)| key).collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn earliest_first() {
+        let now = Instant::now();
+        let mut queue = Queue::new();
+        queue.add(
+            key("repomon", "master"),
+            (),
+            Duration::from_secs(60),
+            now + Duration::from_secs(2),
+        );
+        queue.add(
+            key("ar2", "master"),
+            (),
+            Duration::from_secs(60),
+            now + Duration::from_secs(1),
+        );
+        assert_eq!(queue.next_due(), Some(now + Duration::from_secs(1)));
+        assert!(queue.pop(now).is_none());
+
+        let later = now + Duration::from_secs(2);
+        assert_eq!(keys(queue.pop(later)), vec![key("ar2", "master")]);
+        assert_eq!(keys(queue.pop(later)), vec![key("repomon", "master")]);
+        assert!(queue.pop(later).is_none());
+        assert_eq!(queue.next_due(), None);
+    }
+
+    #[test]
+    fn interval_after_done() {
+        let now = Instant::now();
+        let mut queue = Queue::new();
+        queue.add(key("repomon", "master"), (), Duration::from_secs(60), now);
+        let (key, generation, _) = queue.pop(now).expect("not due").remove(0);
+
+        // Running checks are not due again until they are done.
+        let finished = now + Duration::from_secs(5);
+        assert!(queue.pop(finished).is_none());
+        queue.done("repomon", &[(key, generation)], finished);
+        assert_eq!(queue.next_due(), Some(finished + Duration::from_secs(60)));
+        assert!(queue.pop(finished + Duration::from_secs(59)).is_none());
+        assert!(queue.pop(finished + Duration::from_secs(60)).is_some());
+    }
+
+    #[test]
+    fn check_now() {
+        let now = Instant::now();
+        let mut queue = Queue::new();
+        queue.add(
+            key("repomon", "master"),
+            (),
+            Duration::from_secs(60),
+            now + Duration::from_secs(30),
+        );
+        queue.check(&key("repomon", "master"), now);
+        let (key, generation, _) = queue.pop(now).expect("not due").remove(0);
+        // The stale due time is skipped.
+        assert!(queue.pop(now + Duration::from_secs(30)).is_none());
+
+        // A check requested while one is running runs once it is done.
+        queue.check(&key, now);
+        assert!(queue.pop(now).is_none());
+        queue.done("repomon", &[(key, generation)], now);
+        assert!(queue.pop(now).is_some());
+    }
+
+    #[test]
+    fn remove_and_replace() {
+        let now = Instant::now();
+        let mut queue = Queue::new();
+        queue.add(key("repomon", "master"), 1, Duration::from_secs(60), now);
+        let (key, generation, value) = queue.pop(now).expect("not due").remove(0);
+        assert_eq!(value, 1);
+
+        // A check of a removed, or replaced, entry doesn't schedule it again.
+        queue.remove(&key);
+        queue.done("repomon", &[(key.clone(), generation)], now);
+        assert!(queue.next_due().is_none());
+        queue.add(key.clone(), 2, Duration::from_secs(60), now);
+        queue.done("repomon", &[(key, generation)], now);
+        assert_eq!(queue.pop(now).map(|mut batch| batch.remove(0).2), Some(2));
+        assert!(queue.pop(now).is_none());
+    }
+
+    #[test]
+    fn one_check_per_repository() {
+        let now = Instant::now();
+        let mut queue = Queue::new();
+        queue.add(key("repomon", "master"), (), Duration::from_secs(60), now);
+        queue.add(
+            key("repomon", "dev"),
+            (),
+            Duration::from_secs(3600),
+            now + Duration::from_secs(30),
+        );
+        queue.add(
+            key("ar2", "master"),
+            (),
+            Duration::from_secs(60),
+            now + Duration::from_secs(10),
+        );
+
+        // Only the due branch of the repository is checked.
+        let batch = queue.pop(now).expect("not due");
+        assert_eq!(
+            batch
+                .iter()
+                .map(|(key, _, _)| key.clone())
+                .collect::<Vec<_>>(),
+            vec![key("repomon", "master")]
+        );
+        let checked: Vec<_> = batch
+            .into_iter()
+            .map(|(key, generation, _)| (key, generation))
+            .collect();
+
+        // A branch added to a repository being checked waits for that check.
+        queue.add(
+            key("repomon", "feature/x"),
+            (),
+            Duration::from_secs(60),
+            now,
+        );
+        assert!(queue.pop(now).is_none());
+        let later = now + Duration::from_secs(10);
+        assert_eq!(keys(queue.pop(later)), vec![key("ar2", "master")]);
+        queue.done("repomon", &checked, later);
+
+        // It is then checked right away, but the hourly branch is not.
+        let batch = queue.pop(later).expect("not due");
+        assert_eq!(
+            batch
+                .iter()
+                .map(|(key, _, _)| key.clone())
+                .collect::<Vec<_>>(),
+            vec![key("repomon", "feature/x")]
+        );
+        let checked: Vec<_> = batch
+            .into_iter()
+            .map(|(key, generation, _)| (key, generation))
+            .collect();
+        queue.done("repomon", &checked, later);
+
+        // Branches due together share a check, and each is then due its own
+        // interval later.
+        let due = now + Duration::from_secs(70);
+        let batch = queue.pop(due).expect("not due");
+        assert_eq!(
+            batch
+                .iter()
+                .map(|(key, _, _)| key.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                key("repomon", "dev"),
+                key("repomon", "feature/x"),
+                key("repomon", "master")
+            ]
+        );
+        let checked: Vec<_> = batch
+            .into_iter()
+            .map(|(key, generation, _)| (key, generation))
+            .collect();
+        let finished = due + Duration::from_secs(5);
+        queue.done("repomon", &checked, finished);
+        assert_eq!(
+            queue.entries[&key("repomon", "master")].state,
+            State::Due(finished + Duration::from_secs(60))
+        );
+        assert_eq!(
+            queue.entries[&key("repomon", "dev")].state,
+            State::Due(finished + Duration::from_secs(3600))
+        );
+        assert_eq!(
+            keys(queue.pop(finished + Duration::from_secs(60))),
+            vec![key("repomon", "feature/x"), key("repomon", "master")]
+        );
+    }
+
+    #[test]
+    fn done_on_panic() {
+        let (tx, rx) = channel();
+        let (running, _stopped) = mpsc::unbounded::<()>();
+        let checked = vec![(key("repomon", "master"), 3)];
+        let done = Done {
+            done: tx,
+            repo: "repomon".to_string(),
+            checked: checked.clone(),
+            _running: running,
+            logs: Logs::default(),
+        };
+        assert!(thread::spawn(move || {
+            let _done = done;
+            panic!("check failed");
+        })
+        .join()
+        .is_err());
+
+        match rx.recv() {
+            Ok(Message::Done(repo, done)) => {
+                assert_eq!(repo, "repomon");
+                assert_eq!(done, checked);
+            }
+            _ => panic!("done not sent"),
+        }
+    }
+}
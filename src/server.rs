@@ -0,0 +1,1040 @@
+// Copyright (c) 2017 repomons developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `repomons` server state.
+use auth::Token;
+use config::{HeartbeatConfig, QueueConfig};
+use event::{BranchStatus, Event, RefChange};
+use filter::Filter;
+use monitors;
+use protocol::{Command, Reply, Request, Response, ServerFrame, StatusEvent};
+use queue::{self, SendError, Sent};
+use repomon::Message;
+use scheduler::Control;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The address of a connected client.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Peer {
+    /// A TCP client.
+    Tcp(SocketAddr),
+    /// A WebSocket client.
+    Ws(SocketAddr),
+    /// A Unix socket client.  These are usually unnamed, so are numbered instead.
+    Unix(u64),
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Peer::Tcp(ref addr) => write!(f, "{}", addr),
+            Peer::Ws(ref addr) => write!(f, "ws://{}", addr),
+            Peer::Unix(id) => write!(f, "unix#{}", id),
+        }
+    }
+}
+
+/// A connected client.
+pub struct Client {
+    /// The sender used to queue frames for this client.
+    tx: queue::Sender<ServerFrame>,
+    /// The repositories the client may see, once authenticated.
+    access: Option<Filter>,
+    /// The client subscription filters, by subscription id.
+    subscriptions: BTreeMap<u64, Filter>,
+    /// The next subscription id.
+    next_id: u64,
+    /// When the client last sent a request, once it has pinged.  Clients
+    /// that never ping are not expected to send anything.
+    last_seen: Option<Instant>,
+}
+
+impl Client {
+    /// Create a new client that will be sent frames over the given sender.
+    pub fn new(tx: queue::Sender<ServerFrame>) -> Self {
+        Self {
+            tx,
+            access: None,
+            subscriptions: BTreeMap::new(),
+            next_id: 0,
+            last_seen: None,
+        }
+    }
+
+    /// Is the client interested in the given message?
+    fn wants(&self, message: &Message) -> bool {
+        self.access.iter().any(|access| access.matches(message))
+            && (self.subscriptions.is_empty()
+                || self.subscriptions.values().any(|s| s.matches(message)))
+    }
+}
+
+/// The client queue metrics.
+#[derive(Clone, Debug, Default, Getters, Serialize)]
+pub struct Metrics {
+    /// The number of frames queued, and dropped, for each connected client.
+    #[get = "pub"]
+    clients: BTreeMap<String, QueueMetrics>,
+    /// The number of connected event streams.
+    #[get = "pub"]
+    streams: usize,
+    /// The total number of frames dropped from full queues.
+    #[get = "pub"]
+    dropped: u64,
+    /// The total number of clients disconnected because their queue was full.
+    #[get = "pub"]
+    evicted: u64,
+}
+
+/// The metrics of a single client queue.
+#[derive(Clone, Debug, Default, Getters, Serialize)]
+pub struct QueueMetrics {
+    /// The number of queued frames.
+    #[get = "pub"]
+    queued: usize,
+    /// The number of frames dropped because the queue was full.
+    #[get = "pub"]
+    dropped: u64,
+}
+
+/// The number of published events kept for stream resumption.
+const HISTORY_LEN: usize = 1024;
+
+/// The state shared between the listener, the clients and the monitor receiver.
+#[derive(Default)]
+pub struct Server {
+    /// The connected clients.
+    clients: HashMap<Peer, Client>,
+    /// The event streams, with the repositories they may see and their
+    /// subscription filter, by stream id.
+    streams: BTreeMap<u64, (Filter, Filter, queue::Sender<Event>)>,
+    /// The next stream id.
+    next_stream_id: u64,
+    /// The latest event for each repository/branch.
+    latest: BTreeMap<(String, String), Event>,
+    /// The most recently published events, oldest first.
+    history: VecDeque<Event>,
+    /// The next event id.
+    next_event_id: u64,
+    /// The scheduler controls for each monitored repository/branch.
+    monitors: BTreeMap<(String, String), Control>,
+    /// The remote branches each monitored repository/branch pattern matched
+    /// in its last check.
+    matched: BTreeMap<(String, String), BTreeSet<String>>,
+    /// The branches on each repository/remote, with their OIDs, as of the
+    /// last check.
+    refs: BTreeMap<(String, String), BTreeMap<String, String>>,
+    /// The client tokens.  Clients must authenticate when there are any.
+    tokens: Vec<Token>,
+    /// The time a client has to authenticate before it is disconnected.
+    auth_timeout: Duration,
+    /// The next Unix socket peer id.
+    next_unix_peer: u64,
+    /// The client queue settings.
+    queue: QueueConfig,
+    /// The heartbeat settings.
+    heartbeat: HeartbeatConfig,
+    /// The total number of frames dropped from full queues.
+    dropped: u64,
+    /// The total number of clients disconnected because their queue was full.
+    evicted: u64,
+}
+
+impl Server {
+    /// Require clients to authenticate with one of the given tokens within the given timeout.
+    pub fn set_auth(&mut self, tokens: Vec<Token>, timeout: Duration) {
+        self.tokens = tokens;
+        self.auth_timeout = timeout;
+    }
+
+    /// The time a client has to authenticate before it is disconnected.
+    pub fn auth_timeout(&self) -> &Duration {
+        &self.auth_timeout
+    }
+
+    /// Set the client queue settings.
+    pub fn set_queue(&mut self, queue: QueueConfig) {
+        self.queue = queue;
+    }
+
+    /// Set the heartbeat settings.
+    pub fn set_heartbeat(&mut self, heartbeat: HeartbeatConfig) {
+        self.heartbeat = heartbeat;
+    }
+
+    /// The heartbeat settings.
+    pub fn heartbeat_config(&self) -> &HeartbeatConfig {
+        &self.heartbeat
+    }
+
+    /// Create a client queue.
+    pub fn queue<T>(&self) -> (queue::Sender<T>, queue::Receiver<T>) {
+        self.queue.channel()
+    }
+
+    /// Add a connected client.  The current snapshot is queued for it, unless
+    /// it must authenticate first.
+    pub fn add_client(&mut self, addr: Peer, mut client: Client) -> bool {
+        if self.tokens.is_empty() {
+            let access: Filter = Default::default();
+            let snapshot = ServerFrame::Snapshot(self.snapshot(&access));
+            client.access = Some(access);
+            self.clients.insert(addr, client);
+            self.send(&addr, snapshot).is_ok()
+        } else {
+            self.clients.insert(addr, client);
+            true
+        }
+    }
+
+    /// The repositories a client presenting the given token may see, if it
+    /// may see any.  Every client may see every repository when there are no
+    /// tokens.
+    pub fn access(&self, token: Option<&str>) -> Option<Filter> {
+        if self.tokens.is_empty() {
+            return Some(Default::default());
+        }
+        token.and_then(|token| {
+            self.tokens
+                .iter()
+                .filter_map(|t| t.verify(token))
+                .next()
+                .cloned()
+        })
+    }
+
+    /// Has the client at the given address authenticated?
+    pub fn is_authenticated(&self, addr: &Peer) -> bool {
+        self.clients
+            .get(addr)
+            .and_then(|client| client.access.as_ref())
+            .is_some()
+    }
+
+    /// Allocate the address of a new Unix socket client.
+    pub fn unix_peer(&mut self) -> Peer {
+        let peer = Peer::Unix(self.next_unix_peer);
+        self.next_unix_peer += 1;
+        peer
+    }
+
+    /// Remove a client.
+    pub fn remove_client(&mut self, addr: &Peer) {
+        self.clients.remove(addr);
+    }
+
+    /// Register the scheduler control for a repository/branch monitor.
+    pub fn add_monitor(&mut self, repo: &str, branch: &str, control: Control) {
+        self.monitors
+            .insert((repo.to_string(), branch.to_string()), control);
+    }
+
+    /// Stop and remove the monitor for a repository/branch, along with its
+    /// latest event.  Removing a branch pattern retires the branches it matched.
+    /// Removing the last monitor of a repository forgets its remote branches.
+    pub fn remove_monitor(&mut self, repo: &str, branch: &str) {
+        let key = (repo.to_string(), branch.to_string());
+        if let Some(control) = self.monitors.remove(&key) {
+            control.stop();
+        }
+        self.latest.remove(&key);
+        if let Some(matched) = self.matched.remove(&key) {
+            self.retire(repo, &matched);
+        }
+        if !self.monitors.keys().any(|(monitored, _)| monitored == repo) {
+            self.refs.retain(|(refs_repo, _), _| refs_repo != repo);
+        }
+    }
+
+    /// Record the remote branches a repository/branch pattern matched,
+    /// returning the newly matched and the retired branches.  The latest
+    /// events of the retired branches are removed.
+    pub fn set_matched(
+        &mut self,
+        repo: &str,
+        pattern: &str,
+        branches: BTreeSet<String>,
+    ) -> (Vec<String>, Vec<String>) {
+        let key = (repo.to_string(), pattern.to_string());
+        // Ignore the last check of a pattern removed by a reload.
+        if !self.monitors.contains_key(&key) {
+            return (Vec::new(), Vec::new());
+        }
+
+        let old = self
+            .matched
+            .insert(key, branches.clone())
+            .unwrap_or_default();
+        let added = branches.difference(&old).cloned().collect();
+        let retired: BTreeSet<String> = old.difference(&branches).cloned().collect();
+        self.retire(repo, &retired);
+        (added, retired.into_iter().collect())
+    }
+
+    /// Remove the latest events of the given branches, unless they are still
+    /// monitored.
+    fn retire(&mut self, repo: &str, branches: &BTreeSet<String>) {
+        for branch in branches {
+            if !self.is_monitored(repo, branch) {
+                self.latest.remove(&(repo.to_string(), branch.clone()));
+            }
+        }
+    }
+
+    /// Record the branches on a repository remote, returning the changes
+    /// since the last check.  There are none on the first check.
+    pub fn set_refs(
+        &mut self,
+        repo: &str,
+        remote: &str,
+        refs: BTreeMap<String, String>,
+    ) -> Vec<RefChange> {
+        // Ignore the last check of a repository removed by a reload.
+        if !self.monitors.keys().any(|(monitored, _)| monitored == repo) {
+            return Vec::new();
+        }
+        match self
+            .refs
+            .insert((repo.to_string(), remote.to_string()), refs.clone())
+        {
+            Some(old) => RefChange::diff(&old, &refs),
+            None => Vec::new(),
+        }
+    }
+
+    /// Is the given repository/branch monitored, by name or by a pattern?
+    pub fn is_monitored(&self, repo: &str, branch: &str) -> bool {
+        self.monitors
+            .contains_key(&(repo.to_string(), branch.to_string()))
+            || self
+                .matched
+                .iter()
+                .any(|((r, _), matched)| r == repo && matched.contains(branch))
+    }
+
+    /// The monitored repository/branch pairs, with the branches matched by
+    /// each pattern in place of the pattern.
+    fn monitored(&self) -> BTreeSet<(&String, &String)> {
+        let named = self
+            .monitors
+            .keys()
+            .filter(|(_, branch)| !monitors::is_pattern(branch))
+            .map(|(repo, branch)| (repo, branch));
+        let matched = self
+            .matched
+            .iter()
+            .flat_map(|((repo, _), matched)| matched.iter().map(move |branch| (repo, branch)));
+        named.chain(matched).collect()
+    }
+
+    /// Tell every monitor to stop.
+    pub fn stop_monitors(&self) {
+        for control in self.monitors.values() {
+            control.stop();
+        }
+    }
+
+    /// Add an event stream, that may see the repositories passing the access
+    /// filter, returning the stream id.
+    pub fn add_stream(&mut self, access: Filter, filter: Filter, tx: queue::Sender<Event>) -> u64 {
+        let id = self.next_stream_id;
+        self.next_stream_id += 1;
+        self.streams.insert(id, (access, filter, tx));
+        id
+    }
+
+    /// Remove an event stream.
+    pub fn remove_stream(&mut self, id: u64) {
+        self.streams.remove(&id);
+    }
+
+    /// Queue a frame for the client at the given address.  A client whose
+    /// queue is full is disconnected when the overflow policy says to.
+    fn send(&mut self, addr: &Peer, frame: ServerFrame) -> Result<Sent, SendError> {
+        let result = match self.clients.get(addr) {
+            Some(client) => client.tx.send(frame),
+            None => Err(SendError::Closed),
+        };
+
+        match result {
+            Ok(Sent::Dropped(_)) => self.dropped += 1,
+            Err(SendError::Full) => {
+                self.evicted += 1;
+                self.clients.remove(addr);
+            }
+            _ => {}
+        }
+        result
+    }
+
+    /// Record the given event as the latest, unless it reports a change to
+    /// the branches on a remote, and send it to every interested client and
+    /// stream.  Returns the clients that could not simply be sent
+    /// the event, and why.
+    pub fn publish(&mut self, event: &Event) -> Vec<(Peer, Result<Sent, SendError>)> {
+        self.next_event_id += 1;
+        let mut event = event.clone();
+        event.set_id(self.next_event_id);
+
+        if event.change().is_none() {
+            self.latest.insert(
+                (
+                    event.status().repo().clone(),
+                    event.status().branch().clone(),
+                ),
+                event.clone(),
+            );
+        }
+        self.history.push_back(event.clone());
+        if self.history.len() > HISTORY_LEN {
+            self.history.pop_front();
+        }
+
+        let mut dropped = 0;
+        let mut evicted = 0;
+        self.streams.retain(|_, (access, filter, tx)| {
+            if !access.matches(event.message()) || !filter.matches(event.message()) {
+                return true;
+            }
+            match tx.send(event.clone()) {
+                Ok(Sent::Queued) => true,
+                Ok(Sent::Dropped(_)) => {
+                    dropped += 1;
+                    true
+                }
+                Err(SendError::Full) => {
+                    evicted += 1;
+                    false
+                }
+                Err(SendError::Closed) => false,
+            }
+        });
+        self.dropped += dropped;
+        self.evicted += evicted;
+
+        let message = event.message();
+        let addrs: Vec<Peer> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.wants(message))
+            .map(|(addr, _)| *addr)
+            .collect();
+
+        let frame = ServerFrame::StatusEvent(Box::new(StatusEvent::new(
+            message.clone(),
+            event.status().clone(),
+            event.change().clone(),
+        )));
+        let mut undelivered = Vec::new();
+        for addr in addrs {
+            match self.send(&addr, frame.clone()) {
+                Ok(Sent::Queued) => {}
+                result => undelivered.push((addr, result)),
+            }
+        }
+        undelivered
+    }
+
+    /// Send a heartbeat to every client.  Returns the clients that could not
+    /// simply be sent the heartbeat, and why.
+    pub fn heartbeat(&mut self) -> Vec<(Peer, Result<Sent, SendError>)> {
+        self.broadcast(&ServerFrame::Heartbeat)
+    }
+
+    /// Tell every client the server is shutting down.  Returns the clients
+    /// that could not simply be told, and why.
+    pub fn shutdown(&mut self) -> Vec<(Peer, Result<Sent, SendError>)> {
+        self.broadcast(&ServerFrame::Shutdown)
+    }
+
+    /// Send a frame to every client.
+    fn broadcast(&mut self, frame: &ServerFrame) -> Vec<(Peer, Result<Sent, SendError>)> {
+        let addrs: Vec<Peer> = self.clients.keys().cloned().collect();
+
+        let mut undelivered = Vec::new();
+        for addr in addrs {
+            match self.send(&addr, frame.clone()) {
+                Ok(Sent::Queued) => {}
+                result => undelivered.push((addr, result)),
+            }
+        }
+        undelivered
+    }
+
+    /// Remove the clients that have pinged, but sent nothing since for longer
+    /// than the heartbeat timeout, returning their addresses.
+    pub fn reap(&mut self, now: Instant) -> Vec<Peer> {
+        let timeout = self.heartbeat.timeout();
+        let dead: Vec<Peer> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| {
+                client
+                    .last_seen
+                    .iter()
+                    .any(|last_seen| now.duration_since(*last_seen) > timeout)
+            })
+            .map(|(addr, _)| *addr)
+            .collect();
+
+        for addr in &dead {
+            self.clients.remove(addr);
+        }
+        dead
+    }
+
+    /// The total number of frames queued for the clients.
+    pub fn queued(&self) -> usize {
+        self.clients.values().map(|client| client.tx.queued()).sum()
+    }
+
+    /// The client queue metrics.
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            clients: self
+                .clients
+                .iter()
+                .map(|(addr, client)| {
+                    let metrics = QueueMetrics {
+                        queued: client.tx.queued(),
+                        dropped: client.tx.dropped(),
+                    };
+                    (addr.to_string(), metrics)
+                })
+                .collect(),
+            streams: self.streams.len(),
+            dropped: self.dropped,
+            evicted: self.evicted,
+        }
+    }
+
+    /// The latest message for each repository/branch matching the given filter.
+    pub fn snapshot(&self, filter: &Filter) -> Vec<Message> {
+        self.latest
+            .values()
+            .map(Event::message)
+            .filter(|message| filter.matches(message))
+            .cloned()
+            .collect()
+    }
+
+    /// The events matching the given access and subscription filters that a
+    /// stream resuming after the given event id has missed.  A new stream, one that has missed more than
+    /// the retained history, or one resuming from before a server restart, is
+    /// sent the latest events instead.
+    pub fn replay(
+        &self,
+        last_event_id: Option<u64>,
+        access: &Filter,
+        filter: &Filter,
+    ) -> Vec<Event> {
+        let oldest = self.history.front().map_or(0, |event| *event.id());
+        let mut events: Vec<Event> = match last_event_id {
+            Some(last) if last >= oldest.saturating_sub(1) && last <= self.next_event_id => self
+                .history
+                .iter()
+                .filter(|event| *event.id() > last)
+                .cloned()
+                .collect(),
+            _ => self.latest.values().cloned().collect(),
+        };
+        events.retain(|event| access.matches(event.message()) && filter.matches(event.message()));
+        events.sort_by_key(|event| *event.id());
+        events
+    }
+
+    /// The latest status for each branch of the repositories matching the given predicate.
+    pub fn statuses<F>(&self, predicate: F) -> BTreeMap<String, BTreeMap<String, BranchStatus>>
+    where
+        F: Fn(&BranchStatus) -> bool,
+    {
+        let mut statuses: BTreeMap<String, BTreeMap<String, BranchStatus>> = BTreeMap::new();
+        for event in self
+            .latest
+            .values()
+            .filter(|event| predicate(event.status()))
+        {
+            let status = event.status();
+            statuses
+                .entry(status.repo().clone())
+                .or_default()
+                .insert(status.branch().clone(), status.clone());
+        }
+        statuses
+    }
+
+    /// Handle a request from the client at the given address, queueing the response for it.
+    pub fn handle(&mut self, addr: &Peer, request: &Request) -> bool {
+        if let Some(client) = self.clients.get_mut(addr) {
+            if client.last_seen.is_some() || *request.command() == Command::Ping {
+                client.last_seen = Some(Instant::now());
+            }
+        }
+
+        let result = self.run_command(addr, request.command());
+        let authenticated = match *request.command() {
+            Command::Authenticate(_) => result.is_ok(),
+            _ => false,
+        };
+        let response = Response::new(*request.id(), result);
+        let mut queued = self.send(addr, ServerFrame::Response(response)).is_ok();
+
+        // A newly authenticated client is sent the snapshot it may see.
+        if authenticated {
+            let access = self
+                .clients
+                .get(addr)
+                .and_then(|client| client.access.clone());
+            if let Some(access) = access {
+                let snapshot = ServerFrame::Snapshot(self.snapshot(&access));
+                queued &= self.send(addr, snapshot).is_ok();
+            }
+        }
+        queued
+    }
+
+    /// Run a client command.
+    fn run_command(
+        &mut self,
+        addr: &Peer,
+        command: &Command,
+    ) -> ::std::result::Result<Reply, String> {
+        let access = match *command {
+            Command::Authenticate(ref token) => return self.authenticate(addr, token),
+            Command::Ping => return Ok(Reply::Pong),
+            _ => self
+                .clients
+                .get(addr)
+                .and_then(|client| client.access.clone())
+                .ok_or("not authenticated")?,
+        };
+
+        match *command {
+            Command::Subscribe(ref subscription) => {
+                let filter = Filter::try_from(subscription)
+                    .map_err(|e| format!("invalid subscription: {}", e))?;
+                let client = self.clients.get_mut(addr).ok_or("unknown client")?;
+                let id = client.next_id;
+                client.next_id += 1;
+                client.subscriptions.insert(id, filter);
+                Ok(Reply::Subscribed(id))
+            }
+            Command::Unsubscribe(id) => {
+                let client = self.clients.get_mut(addr).ok_or("unknown client")?;
+                client
+                    .subscriptions
+                    .remove(&id)
+                    .map(|_| Reply::Ok)
+                    .ok_or_else(|| format!("unknown subscription: {}", id))
+            }
+            Command::ListRepos => {
+                let mut repos: BTreeMap<String, Vec<String>> = BTreeMap::new();
+                for (repo, branch) in self
+                    .monitored()
+                    .into_iter()
+                    .filter(|(repo, _)| access.matches_repo(repo))
+                {
+                    repos.entry(repo.clone()).or_default().push(branch.clone());
+                }
+                Ok(Reply::Repos(repos))
+            }
+            Command::Status(ref repo) => Ok(Reply::Status(
+                self.latest
+                    .iter()
+                    .filter(|((r, _), _)| repo.iter().all(|repo| repo == r))
+                    .map(|(_, event)| event.message())
+                    .filter(|message| access.matches(message))
+                    .cloned()
+                    .collect(),
+            )),
+            Command::Check(ref repo, ref branch) => {
+                let mut checked = 0;
+                for ((r, b), control) in &self.monitors {
+                    let matched = self.matched.get(&(r.clone(), b.clone()));
+                    if r == repo
+                        && access.matches_repo(r)
+                        && branch.iter().all(|branch| {
+                            branch == b || matched.is_some_and(|m| m.contains(branch))
+                        })
+                    {
+                        control
+                            .check()
+                            .map_err(|_| format!("monitor stopped: {}/{}", r, b))?;
+                        checked += 1;
+                    }
+                }
+
+                if checked > 0 {
+                    Ok(Reply::Ok)
+                } else {
+                    Err(format!("no matching monitor: {}", repo))
+                }
+            }
+            Command::Authenticate(_) | Command::Ping => {
+                unreachable!("authentication and pings are handled above")
+            }
+        }
+    }
+
+    /// Authenticate the client at the given address with the given token.
+    fn authenticate(&mut self, addr: &Peer, token: &str) -> ::std::result::Result<Reply, String> {
+        let access = self.access(Some(token)).ok_or("invalid token")?;
+        let client = self.clients.get_mut(addr).ok_or("unknown client")?;
+        client.access = Some(access);
+        Ok(Reply::Ok)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Client, Peer, Server};
+    use auth::Token;
+    use config::{QueueConfig, TokenConfig};
+    use event::{BranchStatus, CommitSummary, Event, RefChange, RemoteStatus};
+    use filter::Filter;
+    use futures::Stream;
+    use protocol::{Command, Reply, Request, ServerFrame, Subscription};
+    use queue::{self, Overflow, SendError};
+    use repomon::{Branch, Message};
+    use scheduler::Control;
+    use serde_json;
+    use std::collections::BTreeMap;
+    use std::convert::TryFrom;
+    use std::time::{Duration, Instant};
+
+    fn message(repo: &str) -> Event {
+        let mut branch: Branch = Default::default();
+        branch.set_name("master".to_string());
+        let mut messages = BTreeMap::new();
+        messages.insert(branch, BTreeMap::new());
+
+        let mut message: Message = Default::default();
+        message.set_repo(repo.to_string());
+        message.set_messages(messages);
+        Event::new(message, BranchStatus::new(repo, "master"))
+    }
+
+    #[test]
+    fn subscribe_and_unsubscribe() {
+        let addr = Peer::Tcp("127.0.0.1:1".parse().expect(""));
+        let (tx, rx) = queue::channel(16, Overflow::DropOldest);
+        let mut server: Server = Default::default();
+        assert!(server.add_client(addr, Client::new(tx)));
+
+        let mut subscription: Subscription = Default::default();
+        subscription.set_repos(vec!["repo*".to_string()]);
+
+        let id = match server.run_command(&addr, &Command::Subscribe(subscription)) {
+            Ok(Reply::Subscribed(id)) => id,
+            _ => panic!("invalid subscribe reply"),
+        };
+        assert!(server.publish(&message("ar2")).is_empty());
+        assert!(server.publish(&message("repomon")).is_empty());
+        assert!(server.run_command(&addr, &Command::Unsubscribe(id)).is_ok());
+        assert!(server
+            .run_command(&addr, &Command::Unsubscribe(id))
+            .is_err());
+        assert!(server.publish(&message("ar2")).is_empty());
+        server.remove_client(&addr);
+
+        let repos = rx
+            .wait()
+            .filter_map(|frame| match frame {
+                Ok(ServerFrame::StatusEvent(event)) => Some(event.message().repo().clone()),
+                _ => None,
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(repos, vec!["repomon", "ar2"]);
+    }
+
+    #[test]
+    fn snapshot_on_connect() {
+        let addr = Peer::Tcp("127.0.0.1:1".parse().expect(""));
+        let (tx, rx) = queue::channel(16, Overflow::DropOldest);
+        let mut server: Server = Default::default();
+        assert!(server.publish(&message("repomon")).is_empty());
+        assert!(server.add_client(addr, Client::new(tx)));
+        server.remove_client(&addr);
+
+        match rx.wait().next() {
+            Some(Ok(ServerFrame::Snapshot(messages))) => assert_eq!(messages.len(), 1),
+            _ => panic!("snapshot not sent"),
+        }
+    }
+
+    #[test]
+    fn replay() {
+        let mut server: Server = Default::default();
+        for repo in &["repomon", "ar2", "repomon"] {
+            assert!(server.publish(&message(repo)).is_empty());
+        }
+        let filter: Filter = Default::default();
+
+        let ids = |events: Vec<Event>| events.iter().map(|e| *e.id()).collect::<Vec<u64>>();
+        assert_eq!(ids(server.replay(None, &filter, &filter)), vec![2, 3]);
+        assert_eq!(ids(server.replay(Some(1), &filter, &filter)), vec![2, 3]);
+        assert_eq!(ids(server.replay(Some(0), &filter, &filter)), vec![1, 2, 3]);
+        assert_eq!(
+            ids(server.replay(Some(3), &filter, &filter)),
+            Vec::<u64>::new()
+        );
+        assert_eq!(ids(server.replay(Some(42), &filter, &filter)), vec![2, 3]);
+        assert_eq!(
+            ids(server.replay(Some(u64::MAX), &filter, &filter)),
+            vec![2, 3]
+        );
+    }
+
+    #[test]
+    fn streams() {
+        let (tx, rx) = queue::channel(16, Overflow::DropOldest);
+        let mut server: Server = Default::default();
+        let mut subscription: Subscription = Default::default();
+        subscription.set_repos(vec!["ar2".to_string()]);
+        let filter = Filter::try_from(&subscription).expect("");
+        let id = server.add_stream(Default::default(), filter, tx);
+
+        for repo in &["repomon", "ar2"] {
+            assert!(server.publish(&message(repo)).is_empty());
+        }
+        server.remove_stream(id);
+
+        let ids = rx
+            .wait()
+            .map(|event| *event.expect("").id())
+            .collect::<Vec<u64>>();
+        assert_eq!(ids, vec![2]);
+    }
+
+    #[test]
+    fn authenticate() {
+        let addr = Peer::Tcp("127.0.0.1:1".parse().expect(""));
+        let (tx, rx) = queue::channel(16, Overflow::DropOldest);
+        let mut config: TokenConfig = Default::default();
+        config.set_token("s3cret".to_string());
+        config.set_repos(vec!["repomon*".to_string()]);
+        let mut server: Server = Default::default();
+        server.set_auth(
+            vec![Token::try_from(&config).expect("")],
+            Duration::from_secs(10),
+        );
+        assert!(server.publish(&message("repomon")).is_empty());
+        assert!(server.publish(&message("ar2")).is_empty());
+
+        assert!(server.add_client(addr, Client::new(tx)));
+        assert!(!server.is_authenticated(&addr));
+        assert!(server.run_command(&addr, &Command::ListRepos).is_err());
+        assert!(server
+            .run_command(&addr, &Command::Authenticate("s3cre".to_string()))
+            .is_err());
+        assert!(server.publish(&message("repomon")).is_empty());
+        let request: Request =
+            serde_json::from_str(r#"{"id":1,"command":{"Authenticate":"s3cret"}}"#).expect("");
+        assert!(server.handle(&addr, &request));
+        assert!(server.is_authenticated(&addr));
+        assert!(server.publish(&message("ar2")).is_empty());
+        assert!(server.publish(&message("repomon")).is_empty());
+        server.remove_client(&addr);
+
+        let frames = rx
+            .wait()
+            .map(|frame| match frame.expect("") {
+                ServerFrame::Snapshot(messages) => format!("snapshot {}", messages.len()),
+                ServerFrame::Event(message) => format!("event {}", message.repo()),
+                ServerFrame::StatusEvent(event) => format!("event {}", event.message().repo()),
+                ServerFrame::Response(response) => format!("response {}", response.id()),
+                ServerFrame::Heartbeat => "heartbeat".to_string(),
+                ServerFrame::Shutdown => "shutdown".to_string(),
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(frames, vec!["response 1", "snapshot 1", "event repomon"]);
+    }
+
+    #[test]
+    fn evict() {
+        let addr = Peer::Tcp("127.0.0.1:1".parse().expect(""));
+        let mut config: QueueConfig = Default::default();
+        config.set_capacity(1);
+        config.set_overflow(Overflow::Disconnect);
+        let mut server: Server = Default::default();
+        server.set_queue(config);
+
+        let (tx, rx) = server.queue();
+        assert!(server.add_client(addr, Client::new(tx)));
+        assert_eq!(server.metrics().clients()[&addr.to_string()].queued(), &1);
+        let undelivered = server.publish(&message("repomon"));
+        assert_eq!(undelivered, vec![(addr, Err(SendError::Full))]);
+        assert!(server.metrics().clients().is_empty());
+        assert_eq!(server.metrics().evicted(), &1);
+
+        let frames = rx.wait().collect::<Result<Vec<ServerFrame>, ()>>();
+        assert_eq!(frames.map(|frames| frames.len()), Ok(1));
+    }
+
+    #[test]
+    fn ping_and_reap() {
+        let pinged = Peer::Tcp("127.0.0.1:1".parse().expect(""));
+        let quiet = Peer::Tcp("127.0.0.1:2".parse().expect(""));
+        let mut server: Server = Default::default();
+        let (tx, rx) = queue::channel(16, Overflow::DropOldest);
+        assert!(server.add_client(pinged, Client::new(tx)));
+        let (tx, _quiet_rx) = queue::channel(16, Overflow::DropOldest);
+        assert!(server.add_client(quiet, Client::new(tx)));
+
+        let ping: Request =
+            serde_json::from_str(r#"{"id":1,"command":"Ping"}"#).expect("invalid request");
+        assert!(server.handle(&pinged, &ping));
+        assert!(server.heartbeat().is_empty());
+
+        let timeout = server.heartbeat_config().timeout();
+        assert!(server.reap(Instant::now()).is_empty());
+        let later = Instant::now() + timeout + Duration::from_secs(1);
+        assert_eq!(server.reap(later), vec![pinged]);
+        assert_eq!(server.metrics().clients().len(), 1);
+
+        let frames = rx
+            .wait()
+            .collect::<Result<Vec<ServerFrame>, ()>>()
+            .expect("");
+        assert_eq!(frames.len(), 3);
+        match frames[1] {
+            ServerFrame::Response(ref response) => match *response.result() {
+                Ok(Reply::Pong) => {}
+                ref result => panic!("unexpected result: {:?}", result),
+            },
+            ref frame => panic!("unexpected frame: {:?}", frame),
+        }
+        match frames[2] {
+            ServerFrame::Heartbeat => {}
+            ref frame => panic!("unexpected frame: {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn check_unknown() {
+        let addr = Peer::Tcp("127.0.0.1:1".parse().expect(""));
+        let mut server: Server = Default::default();
+        let command = Command::Check("repomon".to_string(), None);
+        assert!(server.run_command(&addr, &command).is_err());
+    }
+
+    #[test]
+    fn matched_unknown_pattern() {
+        let mut server: Server = Default::default();
+        let _ = server.publish(&message("repomon"));
+        let branches = vec!["master".to_string()].into_iter().collect();
+        let (added, retired) = server.set_matched("repomon", "*", branches);
+        assert!(added.is_empty() && retired.is_empty());
+        assert!(!server.is_monitored("repomon", "master"));
+
+        server.remove_monitor("repomon", "*");
+        assert_eq!(server.statuses(|_| true).len(), 1);
+    }
+
+    #[test]
+    fn ref_changes() {
+        let refs = |branch: &str| -> BTreeMap<String, String> {
+            let mut refs = BTreeMap::new();
+            refs.insert(branch.to_string(), "abc".to_string());
+            refs
+        };
+        let key = |branch: &str| ("repomon".to_string(), branch.to_string());
+        let addr = Peer::Tcp("127.0.0.1:1".parse().expect(""));
+        let (tx, rx) = queue::channel(16, Overflow::DropOldest);
+        let mut server: Server = Default::default();
+        assert!(server.add_client(addr, Client::new(tx)));
+        server.add_monitor("repomon", "master", Control::detached(key("master")));
+        assert!(server
+            .set_refs("repomon", "origin", refs("master"))
+            .is_empty());
+        let changes = server.set_refs("repomon", "origin", refs("main"));
+        assert_eq!(
+            changes,
+            vec![RefChange::Renamed(
+                "master".to_string(),
+                "main".to_string(),
+                "abc".to_string()
+            )]
+        );
+
+        assert!(server
+            .publish(&changes[0].event("repomon", "origin"))
+            .is_empty());
+        server.remove_client(&addr);
+        let sent: Vec<Option<RefChange>> = rx
+            .wait()
+            .filter_map(|frame| match frame {
+                Ok(ServerFrame::StatusEvent(event)) => Some(event.change().clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sent, vec![Some(changes[0].clone())]);
+        assert!(server.statuses(|_| true).is_empty());
+        assert!(server.snapshot(&Default::default()).is_empty());
+        assert_eq!(
+            server
+                .replay(Some(0), &Default::default(), &Default::default())
+                .len(),
+            1
+        );
+        // The branches of a repository removed by a reload are forgotten, so
+        // it starts over once added back.
+        server.add_monitor("repomon", "dev", Control::detached(key("dev")));
+        server.remove_monitor("repomon", "master");
+        assert_eq!(server.set_refs("repomon", "origin", refs("dev")).len(), 1);
+        server.remove_monitor("repomon", "dev");
+        assert!(server
+            .set_refs("repomon", "origin", refs("main"))
+            .is_empty());
+        server.add_monitor("repomon", "master", Control::detached(key("master")));
+        assert!(server
+            .set_refs("repomon", "origin", refs("master"))
+            .is_empty());
+    }
+
+    #[test]
+    fn commit_summaries() {
+        let addr = Peer::Tcp("127.0.0.1:1".parse().expect(""));
+        let (tx, rx) = queue::channel(16, Overflow::DropOldest);
+        let mut server: Server = Default::default();
+        assert!(server.add_client(addr, Client::new(tx)));
+
+        let mut remote = RemoteStatus::new("origin", 0, 1);
+        remote.set_incoming(vec![CommitSummary::new(
+            "37074407c2b984ef297d58981504458fbe91b930",
+            "Jason Ozias",
+            "jason.g.ozias@gmail.com",
+            1_500_000_000,
+            "Add the status API",
+        )]);
+        let mut status = BranchStatus::new("repomon", "master");
+        status.set_remotes(vec![remote]);
+        let event = Event::new(message("repomon").message().clone(), status);
+        assert!(server.publish(&event).is_empty());
+        server.remove_client(&addr);
+
+        let subjects = rx
+            .wait()
+            .filter_map(|frame| match frame {
+                Ok(ServerFrame::StatusEvent(event)) => Some(
+                    event.status().remotes()[0]
+                        .incoming()
+                        .iter()
+                        .map(|commit| commit.subject().clone())
+                        .collect::<Vec<String>>(),
+                ),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(subjects, vec![vec!["Add the status API".to_string()]]);
+    }
+}
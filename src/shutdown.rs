@@ -0,0 +1,173 @@
+// Copyright (c) 2017 repomons developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `repomons` graceful shutdown.
+//!
+//! On SIGINT or SIGTERM the monitors stop starting new fetches, and the
+//! fetches in flight are given the shutdown timeout to finish before they are
+//! cancelled through the transfer callbacks.  Every client is then sent a
+//! final `Shutdown` frame before the server exits.
+use error::{Error, Result};
+use futures::future::Either;
+use futures::sync::mpsc::UnboundedReceiver;
+use futures::{Future, Stream};
+use log::Logs;
+use queue::SendError;
+use scheduler::Scheduler;
+use server::Server;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_core::reactor::{Core, Handle, Interval, Timeout};
+
+/// How long to wait for the monitors once their fetches are cancelled.
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often to check whether the client queues have drained.
+const DRAIN_POLL: Duration = Duration::from_millis(50);
+
+/// The shutdown state shared with the scheduler and the branch checks.
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown {
+    /// Set once the monitors should stop starting new fetches.
+    stopping: Arc<AtomicBool>,
+    /// Set once the fetches in flight should be cancelled.
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    /// Stop the monitors from starting new fetches.
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    /// Is the server shutting down?
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Cancel the fetches in flight.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// The flag the transfer callbacks check to cancel a fetch.
+    pub fn cancelled(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.cancelled)
+    }
+}
+
+/// A future that resolves with the name of the first shutdown signal received.
+#[cfg(unix)]
+pub fn signal(handle: &Handle) -> Box<dyn Future<Item = &'static str, Error = Error>> {
+    use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+
+    let tokio_handle = handle.new_tokio_handle();
+    let first = |signum, name: &'static str| {
+        Signal::with_handle(signum, tokio_handle)
+            .flatten_stream()
+            .into_future()
+            .map(move |_| name)
+            .map_err(|(e, _)| Error::from(e))
+    };
+
+    Box::new(
+        first(SIGINT, "SIGINT")
+            .select(first(SIGTERM, "SIGTERM"))
+            .map(|(name, _)| name)
+            .map_err(|(e, _)| e),
+    )
+}
+
+/// A future that resolves with the name of the first shutdown signal received.
+#[cfg(not(unix))]
+pub fn signal(handle: &Handle) -> Box<dyn Future<Item = &'static str, Error = Error>> {
+    Box::new(
+        ::tokio_signal::ctrl_c_handle(handle.new_tokio_handle())
+            .flatten_stream()
+            .into_future()
+            .map(|_| "CTRL-C")
+            .map_err(|(e, _)| Error::from(e)),
+    )
+}
+
+/// Shut the server down.  `monitors` ends once the scheduler thread, and
+/// every check in flight, is done.
+pub fn run(
+    core: &mut Core,
+    server: &Rc<RefCell<Server>>,
+    scheduler: &Scheduler,
+    state: &Shutdown,
+    mut monitors: UnboundedReceiver<()>,
+    timeout: Duration,
+    logs: &Logs,
+) -> Result<()> {
+    state.stop();
+    server.borrow().stop_monitors();
+    scheduler.stop();
+
+    // Give the fetches in flight until the timeout to finish, then cancel them.
+    if !run_for(core, monitors.by_ref().for_each(|_| Ok(())), timeout)? {
+        try_warn!(logs.stdout(), "Cancelling fetches in flight");
+        state.cancel();
+        if !run_for(core, monitors.by_ref().for_each(|_| Ok(())), CANCEL_TIMEOUT)? {
+            try_warn!(logs.stdout(), "Monitors did not stop in time");
+        }
+    }
+
+    for (addr, result) in server.borrow_mut().shutdown() {
+        if let Err(SendError::Full) = result {
+            try_warn!(logs.stdout(), "Client queue full, disconnecting"; "addr" => format!("{}", addr));
+        }
+    }
+
+    // Give the clients until the timeout to be sent everything queued for them.
+    let drain_server = Rc::clone(server);
+    let drained = Interval::new(DRAIN_POLL, &core.handle())?
+        .map_err(|_| ())
+        .take_while(move |_| Ok(drain_server.borrow().queued() > 0))
+        .for_each(|_| Ok(()));
+    if !run_for(core, drained, timeout)? {
+        try_warn!(logs.stdout(), "Clients did not drain in time");
+    }
+    Ok(())
+}
+
+/// Run the event loop until the given future resolves, or the timeout passes.
+/// Returns whether the future resolved in time.
+fn run_for<F>(core: &mut Core, future: F, timeout: Duration) -> Result<bool>
+where
+    F: Future<Item = (), Error = ()>,
+{
+    let timeout = Timeout::new(timeout, &core.handle())?;
+    match core.run(future.select2(timeout)) {
+        Ok(Either::A(_)) | Err(Either::A(_)) => Ok(true),
+        Ok(Either::B(_)) => Ok(false),
+        Err(Either::B((e, _))) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Shutdown;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn stop_and_cancel() {
+        let shutdown: Shutdown = Default::default();
+        let cancelled = shutdown.cancelled();
+        let monitor = shutdown.clone();
+        assert!(!monitor.is_stopping());
+        shutdown.stop();
+        assert!(monitor.is_stopping());
+        assert!(!cancelled.load(Ordering::SeqCst));
+        shutdown.cancel();
+        assert!(cancelled.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright (c) 2017 repomons developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `repomons` Server-Sent Events stream.
+//!
+//! `GET /events` streams one event per monitor message.  The event type is
+//! the message category and the data is the JSON message, with the status of
+//! its branch.  The stream can be
+//! filtered with repeated `repo` (glob), `branch` and `category` query
+//! parameters, and is resumed after the `Last-Event-ID` request header.  When
+//! tokens are configured, browsers, which can't set the `Authorization`
+//! header of an `EventSource`, may pass the token as the `token` parameter.
+use error::Result;
+use event::Event;
+use filter::Filter;
+use futures::{stream, Future, Sink, Stream};
+use hyper::server::{Request, Response};
+use hyper::{self, Body, Chunk};
+use json;
+use log::Logs;
+use protocol::Subscription;
+use repomon::Category;
+use server::Server;
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::rc::Rc;
+use tokio_core::reactor::Handle;
+use url::form_urlencoded;
+
+/// Start a new event stream for the given request, of the events in the
+/// repositories passing the access filter.
+pub fn events(
+    req: &Request,
+    access: Filter,
+    handle: &Handle,
+    server: &Rc<RefCell<Server>>,
+    logs: &Logs,
+) -> Result<Response> {
+    let filter = Filter::try_from(&subscription(req.query().unwrap_or(""))?)?;
+    let last_event_id = match req
+        .headers()
+        .get_raw("Last-Event-ID")
+        .and_then(|raw| raw.one())
+    {
+        Some(bytes) => Some(
+            String::from_utf8_lossy(bytes)
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| "invalid Last-Event-ID")?,
+        ),
+        None => None,
+    };
+
+    // The server is only borrowed on this thread, so nothing can be published
+    // between the replay and registering the stream.
+    let (tx, rx) = server.borrow().queue();
+    let closed = rx.closed();
+    let backlog = server.borrow().replay(last_event_id, &access, &filter);
+    let id = server.borrow_mut().add_stream(access, filter, tx);
+    try_trace!(logs.stdout(), "Event stream opened"; "stream" => id);
+
+    let chunks = stream::iter_ok(backlog).chain(rx).and_then(|event| {
+        format(&event)
+            .map(|text| Ok::<Chunk, hyper::Error>(Chunk::from(text)))
+            .map_err(|_| ())
+    });
+    let (sender, body) = Body::pair();
+    let stream_server = Rc::clone(server);
+    let stream_logs = logs.clone();
+    let stream = sender
+        .sink_map_err(|_| ())
+        .send_all(chunks)
+        .map(|_| ())
+        .select(closed);
+    handle.spawn(stream.then(move |_| {
+        try_trace!(stream_logs.stdout(), "Event stream closed"; "stream" => id);
+        stream_server.borrow_mut().remove_stream(id);
+        Ok(())
+    }));
+
+    let mut response = Response::new().with_body(body);
+    response
+        .headers_mut()
+        .set_raw("Content-Type", "text/event-stream");
+    response.headers_mut().set_raw("Cache-Control", "no-cache");
+    Ok(response)
+}
+
+/// Build a subscription from the request query string.
+fn subscription(query: &str) -> Result<Subscription> {
+    let mut subscription: Subscription = Default::default();
+    let mut repos = Vec::new();
+    let mut branches = Vec::new();
+    let mut categories = Vec::new();
+
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        match &*key {
+            "repo" => repos.push(value.into_owned()),
+            "branch" => branches.push(value.into_owned()),
+            "category" => categories.push(category(&value)?),
+            "token" => {}
+            _ => return Err(format!("invalid query parameter: {}", key).into()),
+        }
+    }
+
+    subscription.set_repos(repos);
+    subscription.set_branches(branches);
+    subscription.set_categories(categories);
+    Ok(subscription)
+}
+
+/// Parse a category name.
+fn category(name: &str) -> Result<Category> {
+    match name {
+        "Info" => Ok(Category::Info),
+        "Ahead" => Ok(Category::Ahead),
+        "Behind" => Ok(Category::Behind),
+        "UpToDate" => Ok(Category::UpToDate),
+        _ => Err(format!("invalid category: {}", name).into()),
+    }
+}
+
+/// Format an event in the `text/event-stream` format.
+fn format(event: &Event) -> Result<String> {
+    Ok(format!(
+        "id: {}\nevent: {}\ndata: {}\n\n",
+        event.id(),
+        event.message().category(),
+        json::event_to_string(event)?
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{TimeZone, Utc};
+    use event::{BranchStatus, Event};
+    use repomon::{Category, Message};
+    use uuid::Uuid;
+
+    #[test]
+    fn subscription() {
+        let subscription =
+            super::subscription("repo=repomon*&repo=ar2&branch=feature%2Fx&category=Behind")
+                .expect("invalid query");
+        assert_eq!(subscription.repos(), &["repomon*", "ar2"]);
+        assert_eq!(subscription.branches(), &["feature/x"]);
+        assert_eq!(subscription.categories(), &[Category::Behind]);
+        assert!(super::subscription("").is_ok());
+        assert!(super::subscription("token=s3cret").is_ok());
+        assert!(super::subscription("category=Sideways").is_err());
+        assert!(super::subscription("remote=origin").is_err());
+    }
+
+    #[test]
+    fn format() {
+        let mut message: Message = Default::default();
+        message.set_uuid(Uuid::nil());
+        message.set_category(Category::Behind);
+        message.set_repo("repomon".to_string());
+        let mut status = BranchStatus::new("repomon", "master");
+        status.set_checked(Utc.timestamp(1_500_000_000, 0));
+        let mut event = Event::new(message, status);
+        event.set_id(7);
+
+        assert_eq!(
+            super::format(&event).expect(""),
+            "id: 7\nevent: Behind\ndata: {\"uuid\":\"00000000-0000-0000-0000-000000000000\",\"category\":\"Behind\",\"repo\":\"repomon\",\"messages\":{},\"status\":{\"repo\":\"repomon\",\"branch\":\"master\",\"checked\":\"2017-07-14T02:40:00Z\",\"remotes\":[],\"mirrors\":[]}}\n\n"
+        );
+    }
+}
@@ -0,0 +1,196 @@
+// Copyright (c) 2017 repomons developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `repomons` TLS for the monitor listener.
+//!
+//! The OpenSSL handshake is driven on the event loop by retrying it whenever
+//! the underlying non-blocking stream would block.  A client that doesn't
+//! finish the handshake in time is disconnected.
+use config::TlsConfig;
+use error::{Error, Result};
+use futures::future::{self, Either};
+use futures::{Async, Future, Poll};
+use openssl::ssl::{
+    HandshakeError, MidHandshakeSslStream, SslAcceptor, SslFiletype, SslMethod, SslStream,
+    SslVerifyMode,
+};
+use std::fmt::Debug;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// The time a client has to finish the TLS handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Build a TLS acceptor from the given settings.
+pub fn acceptor(config: &TlsConfig) -> Result<SslAcceptor> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_certificate_chain_file(config.cert())?;
+    builder.set_private_key_file(config.key(), SslFiletype::PEM)?;
+    builder.check_private_key()?;
+
+    if let Some(ref client_ca) = *config.client_ca() {
+        builder.set_ca_file(client_ca)?;
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+
+    Ok(builder.build())
+}
+
+/// Accept a TLS connection on the given stream, failing once the timeout
+/// passes.  The stream is dropped, and so closed, along with the handshake.
+pub fn accept<S>(
+    acceptor: &SslAcceptor,
+    stream: S,
+    timeout: Duration,
+    handle: &Handle,
+) -> Box<dyn Future<Item = TlsStream<S>, Error = Error>>
+where
+    S: Debug + Read + Write + 'static,
+{
+    let timeout = match Timeout::new(timeout, handle) {
+        Ok(timeout) => timeout,
+        Err(e) => return Box::new(future::err(e.into())),
+    };
+    let handshake = Accept {
+        state: State::Start(acceptor.clone(), stream),
+    };
+    Box::new(handshake.select2(timeout).then(|result| match result {
+        Ok(Either::A((stream, _))) => Ok(stream),
+        Ok(Either::B(_)) => Err("TLS handshake timed out".into()),
+        Err(Either::A((e, _))) => Err(e),
+        Err(Either::B((e, _))) => Err(e.into()),
+    }))
+}
+
+/// The state of a TLS handshake.
+enum State<S> {
+    /// The handshake has not been started.
+    Start(SslAcceptor, S),
+    /// The handshake is waiting on the stream.
+    Handshaking(MidHandshakeSslStream<S>),
+    /// The handshake has finished.
+    Done,
+}
+
+/// A future resolving to a TLS stream once the handshake has finished.
+pub struct Accept<S> {
+    /// The handshake state.
+    state: State<S>,
+}
+
+impl<S: Debug + Read + Write> Future for Accept<S> {
+    type Item = TlsStream<S>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = match mem::replace(&mut self.state, State::Done) {
+            State::Start(acceptor, stream) => acceptor.accept(stream),
+            State::Handshaking(mid) => mid.handshake(),
+            State::Done => panic!("TLS handshake polled after completion"),
+        };
+
+        match result {
+            Ok(stream) => Ok(Async::Ready(TlsStream(stream))),
+            Err(HandshakeError::WouldBlock(mid)) => {
+                self.state = State::Handshaking(mid);
+                Ok(Async::NotReady)
+            }
+            Err(e) => Err(format!("TLS handshake failed: {}", e).into()),
+        }
+    }
+}
+
+/// A TLS stream usable on the event loop.
+pub struct TlsStream<S>(SslStream<S>);
+
+impl<S: Read + Write> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for TlsStream<S> {}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for TlsStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self.0.shutdown() {
+            Ok(_) => {}
+            Err(e) => match e.into_io_error() {
+                Ok(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Ok(e) => return Err(e),
+                // The peer may already be gone, which is fine when closing.
+                Err(_) => {}
+            },
+        }
+        self.0.get_mut().shutdown()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::acceptor;
+    use config::TlsConfig;
+    use openssl::ssl::{SslAcceptor, SslMethod};
+    use std::io::{self, Read, Write};
+    use std::time::Duration;
+    use tokio_core::reactor::Core;
+
+    /// A client that never sends anything.
+    #[derive(Debug)]
+    struct Silent;
+
+    impl Read for Silent {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl Write for Silent {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        let mut core = Core::new().expect("");
+        let acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls())
+            .expect("")
+            .build();
+        let handshake = super::accept(&acceptor, Silent, Duration::from_millis(10), &core.handle());
+        match core.run(handshake) {
+            Err(e) => assert_eq!(e.to_string(), "TLS handshake timed out"),
+            Ok(_) => panic!("handshake finished"),
+        }
+    }
+
+    #[test]
+    fn missing_files() {
+        let mut config: TlsConfig = Default::default();
+        config.set_cert("/nonexistent/cert.pem".into());
+        config.set_key("/nonexistent/key.pem".into());
+        assert!(acceptor(&config).is_err());
+    }
+}
@@ -0,0 +1,200 @@
+// Copyright (c) 2017 repomons developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `repomons` WebSocket transport.
+//!
+//! WebSocket clients are sent the same frames as the TCP stream clients, as
+//! JSON text messages, and may send the same requests as JSON text messages.
+use auth;
+use error::Result;
+use futures::{Future, Sink, Stream};
+use json;
+use log::Logs;
+use protocol::{Request, ServerFrame};
+use serde_json;
+use server::{Client, Peer, Server};
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Handle;
+use websocket::async::server::IntoWs;
+use websocket::async::Client as WsClient;
+use websocket::OwnedMessage;
+
+/// Serve the WebSocket transport on the given address.
+pub fn serve(
+    addr: &SocketAddr,
+    handle: &Handle,
+    server: Rc<RefCell<Server>>,
+    logs: &Logs,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr, handle)?;
+    try_trace!(logs.stdout(), "Listening for WebSocket connections"; "addr" => format!("{}", addr));
+
+    let conn_handle = handle.clone();
+    let conn_logs = logs.clone();
+    let srv = listener
+        .incoming()
+        .for_each(move |(stream, addr)| {
+            try_trace!(conn_logs.stdout(), "WebSocket connection opened"; "addr" => format!("{}", addr));
+            let client_handle = conn_handle.clone();
+            let client_server = Rc::clone(&server);
+            let client_logs = conn_logs.clone();
+            let error_logs = conn_logs.clone();
+            let upgrade = stream
+                .into_ws()
+                .map_err(|(_, _, _, e)| e.to_string())
+                .and_then(|upgrade| upgrade.accept().map_err(|e| e.to_string()))
+                .map(move |(client, _)| {
+                    connected(
+                        &client_handle,
+                        &client_server,
+                        client,
+                        Peer::Ws(addr),
+                        &client_logs,
+                    )
+                })
+                .map_err(move |e| {
+                    try_error!(error_logs.stderr(), "WebSocket handshake failed: {}", e; "addr" => format!("{}", addr))
+                });
+            conn_handle.spawn(upgrade);
+            Ok(())
+        })
+        .map_err(|_| ());
+    handle.spawn(srv);
+    Ok(())
+}
+
+/// Register a WebSocket client, and spawn its reader and writer.
+fn connected(
+    handle: &Handle,
+    server: &Rc<RefCell<Server>>,
+    client: WsClient<TcpStream>,
+    addr: Peer,
+    logs: &Logs,
+) {
+    let (sink, stream) = client.split();
+
+    // Both halves stop once the client is disconnected, e.g. for letting its
+    // queue fill up.
+    let (tx, rx) = server.borrow().queue();
+    let writer_closed = rx.closed();
+    let reader_closed = rx.closed();
+    if !server.borrow_mut().add_client(addr, Client::new(tx)) {
+        try_error!(logs.stderr(), "Error sending snapshot"; "addr" => format!("{}", addr));
+    }
+
+    // Send every frame we receive on the Receiver as a JSON text message.
+    let writer_logs = logs.clone();
+    let writer = rx
+        .and_then(move |frame| {
+            try_trace!(writer_logs.stdout(), "Sending JSON frame"; "addr" => format!("{}", addr));
+            message(&frame).map_err(|_| ())
+        })
+        .forward(sink.sink_map_err(|_| ()))
+        .map(|_| ())
+        .select(writer_closed);
+
+    let writer_server = Rc::clone(server);
+    let writer_logs = logs.clone();
+    handle.spawn(writer.then(move |_| {
+        try_trace!(writer_logs.stdout(), "Closing WebSocket connection"; "addr" => format!("{}", addr));
+        writer_server.borrow_mut().remove_client(&addr);
+        Ok(())
+    }));
+
+    // Handle each JSON request text message, until the client closes the
+    // connection or fails to authenticate in time.
+    let reader_server = Rc::clone(server);
+    let reader_logs = logs.clone();
+    let reader = stream
+        .take_while(|message| Ok(!message.is_close()))
+        .for_each(move |message| {
+            if let Some(request) = request(&message) {
+                match request {
+                    Ok(request) => {
+                        if !reader_server.borrow_mut().handle(&addr, &request) {
+                            try_error!(reader_logs.stderr(), "Error sending response"; "addr" => format!("{}", addr));
+                        }
+                    }
+                    Err(e) => {
+                        try_error!(reader_logs.stderr(), "Invalid request: {}", e; "addr" => format!("{}", addr))
+                    }
+                }
+            }
+            Ok(())
+        });
+    let deadline_logs = logs.clone();
+    let deadline = auth::deadline(handle, server, addr).map(move |_| {
+        try_warn!(deadline_logs.stdout(), "Authentication timed out"; "addr" => format!("{}", addr))
+    });
+    let reader = reader
+        .map_err(|_| ())
+        .select(deadline)
+        .map(|_| ())
+        .map_err(|_| ())
+        .select(reader_closed);
+
+    let reader_server = Rc::clone(server);
+    let reader_logs = logs.clone();
+    handle.spawn(reader.then(move |_| {
+        try_trace!(reader_logs.stdout(), "WebSocket connection closed by client"; "addr" => format!("{}", addr));
+        reader_server.borrow_mut().remove_client(&addr);
+        Ok(())
+    }));
+}
+
+/// The JSON text message a frame is sent as.
+fn message(frame: &ServerFrame) -> Result<OwnedMessage> {
+    json::to_string(frame).map(OwnedMessage::Text)
+}
+
+/// The request in a message, if it is a text message.
+fn request(message: &OwnedMessage) -> Option<Result<Request>> {
+    match *message {
+        OwnedMessage::Text(ref text) => Some(serde_json::from_str(text).map_err(Into::into)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use protocol::{Command, ServerFrame};
+    use server::Peer;
+    use std::net::SocketAddr;
+    use websocket::OwnedMessage;
+
+    #[test]
+    fn peer() {
+        let addr: SocketAddr = "127.0.0.1:1".parse().expect("");
+        assert_ne!(Peer::Ws(addr), Peer::Tcp(addr));
+        assert_eq!(Peer::Ws(addr).to_string(), "ws://127.0.0.1:1");
+    }
+
+    #[test]
+    fn frames() {
+        match super::message(&ServerFrame::Snapshot(Vec::new())).expect("") {
+            OwnedMessage::Text(text) => assert_eq!(text, r#"{"Snapshot":[]}"#),
+            message => panic!("unexpected message: {:?}", message),
+        }
+
+        let list = OwnedMessage::Text(r#"{"id":1,"command":"ListRepos"}"#.to_string());
+        match super::request(&list) {
+            Some(Ok(request)) => {
+                assert_eq!(request.id(), &1);
+                assert_eq!(request.command(), &Command::ListRepos);
+            }
+            request => panic!("unexpected request: {:?}", request),
+        }
+        assert!(super::request(&OwnedMessage::Text("{}".to_string()))
+            .expect("")
+            .is_err());
+        assert!(super::request(&OwnedMessage::Ping(Vec::new())).is_none());
+    }
+}